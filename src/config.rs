@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use serde::de::{self, Deserializer, Visitor};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::formatter::{Formatter, Grouping, JunkColumn, NewlineStyle, Overflow};
+use crate::language::Language;
+
+/// The names a config file may have, in order of preference within one directory.
+const FILE_NAMES: &[&str] = &["pythonicfmt.toml", ".pythonicfmt.toml"];
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Couldn't read config file {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("Invalid config file {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+    #[error("Invalid glob in config file {0}: {1}")]
+    Glob(PathBuf, ignore::Error),
+    #[error("Unknown keys in config file {0}: {}", .1.join(", "))]
+    UnknownKeys(PathBuf, Vec<String>),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Formatter settings, where anything left unset keeps its previous value.
+#[derive(Deserialize, Default, Debug)]
+pub(crate) struct Settings {
+    pub(crate) junk_column: Option<JunkColumn>,
+    pub(crate) grouping: Option<Grouping>,
+    pub(crate) overflow: Option<Overflow>,
+    pub(crate) warn_overflow: Option<bool>,
+    pub(crate) junk_chars: Option<String>,
+    pub(crate) comment_column: Option<usize>,
+    pub(crate) language: Option<Language>,
+    pub(crate) newline_style: Option<NewlineStyle>,
+}
+
+impl Settings {
+    pub(crate) fn apply(&self, formatter: &mut Formatter) {
+        if let Some(junk_column) = self.junk_column {
+            formatter.junk_column(junk_column);
+        }
+        if let Some(grouping) = self.grouping {
+            formatter.grouping(grouping);
+        }
+        if let Some(overflow) = self.overflow {
+            formatter.overflow(overflow);
+        }
+        if let Some(warn_overflow) = self.warn_overflow {
+            formatter.warn_overflow(warn_overflow);
+        }
+        if let Some(junk_chars) = &self.junk_chars {
+            formatter.junk_chars(junk_chars);
+        }
+        if let Some(comment_column) = self.comment_column {
+            formatter.comment_column(comment_column);
+        }
+        if let Some(language) = self.language {
+            formatter.language(language);
+        }
+        if let Some(newline_style) = self.newline_style {
+            formatter.newline_style(newline_style);
+        }
+    }
+}
+
+/// A `pythonicfmt.toml` file, which applies to everything in its directory and below.
+///
+/// ```toml
+/// junk_column = 100
+/// overflow = "tab:4"
+/// exclude = ["vendor/", "*.generated.c"]
+///
+/// [extensions]
+/// tpp = "cpp"
+///
+/// [[overrides]]
+/// files = ["*.h"]
+/// junk_column = 80
+/// ```
+#[derive(Debug)]
+pub(crate) struct Config {
+    settings: Settings,
+    /// Languages for extensions we wouldn't otherwise know, keyed in lowercase
+    extensions: HashMap<String, Language>,
+    exclude: Gitignore,
+    /// Applied in order after `settings`, so later ones win
+    overrides: Vec<(Gitignore, Settings)>,
+}
+
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(flatten)]
+    settings: Settings,
+    #[serde(default)]
+    extensions: HashMap<String, Language>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(default)]
+    overrides: Vec<OverrideSection>,
+    // Whatever none of the above took, which is most likely a typo
+    #[serde(flatten)]
+    rest: toml::Table,
+}
+
+#[derive(Deserialize)]
+struct OverrideSection {
+    files: Vec<String>,
+    #[serde(flatten)]
+    settings: Settings,
+    #[serde(flatten)]
+    rest: toml::Table,
+}
+
+impl Config {
+    fn load(path: &Path) -> Result<Config> {
+        let text = std::fs::read_to_string(path).map_err(|e| Error::Io(path.to_owned(), e))?;
+        let file: ConfigFile =
+            toml::from_str(&text).map_err(|e| Error::Parse(path.to_owned(), e))?;
+        let unknown: Vec<_> =
+            file.rest
+                .keys()
+                .cloned()
+                .chain(file.overrides.iter().flat_map(|section| {
+                    section.rest.keys().map(|key| format!("overrides.{}", key))
+                }))
+                .collect();
+        if !unknown.is_empty() {
+            return Err(Error::UnknownKeys(path.to_owned(), unknown));
+        }
+        let overrides = file
+            .overrides
+            .into_iter()
+            .map(|section| Ok((globs(path, &section.files)?, section.settings)))
+            .collect::<Result<_>>()?;
+        Ok(Config {
+            settings: file.settings,
+            extensions: file
+                .extensions
+                .into_iter()
+                .map(|(ext, language)| (ext.trim_start_matches('.').to_ascii_lowercase(), language))
+                .collect(),
+            exclude: globs(path, &file.exclude)?,
+            overrides,
+        })
+    }
+
+    /// Applies the settings for `path`, which includes any overrides whose globs match it.
+    pub(crate) fn apply(&self, formatter: &mut Formatter, path: Option<&Path>) {
+        self.settings.apply(formatter);
+        let path = match path {
+            Some(path) => path,
+            None => return,
+        };
+        for (files, settings) in &self.overrides {
+            if matches(files, path) {
+                settings.apply(formatter);
+            }
+        }
+    }
+
+    /// The language configured for the extension of `path`, if any.
+    pub(crate) fn language_for(&self, path: &Path) -> Option<Language> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        self.extensions.get(&extension).copied()
+    }
+
+    pub(crate) fn is_excluded(&self, path: &Path) -> bool {
+        matches(&self.exclude, path)
+    }
+}
+
+// Globs are relative to the config file, like in a .gitignore
+fn globs(config_path: &Path, globs: &[String]) -> Result<Gitignore> {
+    let dir = config_path
+        .parent()
+        .expect("Config file without a directory");
+    let mut builder = GitignoreBuilder::new(dir);
+    for glob in globs {
+        builder
+            .add_line(None, glob)
+            .map_err(|e| Error::Glob(config_path.to_owned(), e))?;
+    }
+    builder
+        .build()
+        .map_err(|e| Error::Glob(config_path.to_owned(), e))
+}
+
+fn matches(globs: &Gitignore, path: &Path) -> bool {
+    let path = resolve(path);
+    match path.strip_prefix(globs.path()) {
+        Ok(relative) => globs
+            .matched_path_or_any_parents(relative, false)
+            .is_ignore(),
+        Err(_) => false,
+    }
+}
+
+/// Finds the config file for each path, remembering what each directory turned up.
+#[derive(Default)]
+pub(crate) struct Configs {
+    by_dir: HashMap<PathBuf, Option<Arc<Config>>>,
+}
+
+impl Configs {
+    /// The nearest config file in the directory of `path` or any directory above it.
+    pub(crate) fn for_file(&mut self, path: &Path) -> Result<Option<Arc<Config>>> {
+        let path = resolve(path);
+        self.find(path.parent().unwrap_or(&path))
+    }
+
+    /// The nearest config file in `dir` or any directory above it.
+    pub(crate) fn for_dir(&mut self, dir: &Path) -> Result<Option<Arc<Config>>> {
+        self.find(&resolve(dir))
+    }
+
+    fn find(&mut self, dir: &Path) -> Result<Option<Arc<Config>>> {
+        if let Some(config) = self.by_dir.get(dir) {
+            return Ok(config.clone());
+        }
+        let config = match FILE_NAMES
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|p| p.is_file())
+        {
+            Some(path) => Some(Arc::new(Config::load(&path)?)),
+            None => match dir.parent() {
+                Some(parent) => self.find(parent)?,
+                None => None,
+            },
+        };
+        self.by_dir.insert(dir.to_owned(), config.clone());
+        Ok(config)
+    }
+}
+
+// Makes a path absolute so we can walk up from it and match excludes against it
+fn resolve(path: &Path) -> PathBuf {
+    let path = if path.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        path
+    };
+    path.canonicalize()
+        .or_else(|_| std::path::absolute(path))
+        .unwrap_or_else(|_| path.to_owned())
+}
+
+/// Lets config values be written the same way as on the command line.
+struct FromStrVisitor<T>(PhantomData<T>);
+
+impl<'de, T> Visitor<'de> for FromStrVisitor<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a string or an integer")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> std::result::Result<T, E> {
+        value.parse().map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> std::result::Result<T, E> {
+        self.visit_str(&value.to_string())
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> std::result::Result<T, E> {
+        self.visit_str(&value.to_string())
+    }
+}
+
+macro_rules! deserialize_from_str {
+    ($($ty:ty),*) => {
+        $(
+            impl<'de> Deserialize<'de> for $ty {
+                fn deserialize<D: Deserializer<'de>>(
+                    deserializer: D,
+                ) -> std::result::Result<Self, D::Error> {
+                    deserializer.deserialize_any(FromStrVisitor(PhantomData))
+                }
+            }
+        )*
+    };
+}
+
+deserialize_from_str!(JunkColumn, Grouping, Overflow, NewlineStyle, Language);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> (tempfile::TempDir, Arc<Config>) {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("pythonicfmt.toml"), toml).unwrap();
+        let config = Configs::default().for_dir(dir.path()).unwrap().unwrap();
+        (dir, config)
+    }
+
+    // Where the config puts the junk for `path`, which needn't exist
+    fn junk_column(config: &Config, path: &Path) -> usize {
+        let mut formatter = Formatter::default();
+        config.apply(&mut formatter, Some(path));
+        let mut content = "a;\n".to_string();
+        formatter.format(&mut content).unwrap();
+        content.find(';').unwrap()
+    }
+
+    #[test]
+    fn globs_match_relative_to_the_config() {
+        let (dir, config) = config(
+            r#"
+exclude = ["tests/**", "vendor/"]
+
+[[overrides]]
+files = ["*.h"]
+junk_column = 40
+"#,
+        );
+        let dir = dir.path();
+        assert!(config.is_excluded(&dir.join("tests/unit/a.c")));
+        assert!(!config.is_excluded(&dir.join("src/tests/a.c")));
+        assert!(config.is_excluded(&dir.join("vendor/lib/a.c")));
+        assert!(!config.is_excluded(&dir.join("vendor.c")));
+        assert!(!config.is_excluded(&dir.join("src/a.c")));
+        assert_eq!(junk_column(&config, &dir.join("include/deep/a.h")), 40);
+        assert_eq!(junk_column(&config, &dir.join("a.c")), 120);
+    }
+
+    #[test]
+    fn later_overrides_win() {
+        let (dir, config) = config(
+            r#"
+junk_column = 100
+
+[[overrides]]
+files = ["*.h"]
+junk_column = 80
+
+[[overrides]]
+files = ["include/"]
+junk_column = 60
+"#,
+        );
+        let dir = dir.path();
+        assert_eq!(junk_column(&config, &dir.join("include/a.h")), 60);
+        assert_eq!(junk_column(&config, &dir.join("src/a.h")), 80);
+        assert_eq!(junk_column(&config, &dir.join("src/a.c")), 100);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("pythonicfmt.toml"),
+            "junk_colum = 100\n[[overrides]]\nfiles = [\"*.h\"]\ngroupin = \"block\"\n",
+        )
+        .unwrap();
+        match Configs::default().for_dir(dir.path()) {
+            Err(Error::UnknownKeys(_, keys)) => {
+                assert_eq!(keys, ["junk_colum", "overrides.groupin"]);
+            }
+            _ => panic!("Expected unknown keys"),
+        }
+    }
+}
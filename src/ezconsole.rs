@@ -1,5 +1,81 @@
+use std::io::Write;
+
 use console::{style, StyledObject};
 
+use crate::formatter::Span;
+
 pub(crate) fn style_e<D>(val: D) -> StyledObject<D> {
     style(val).for_stderr()
 }
+
+pub(crate) fn style_o<D>(val: D) -> StyledObject<D> {
+    style(val).for_stdout()
+}
+
+pub(crate) enum Severity {
+    Warning,
+    Error,
+}
+
+/// Print a problem to stderr, see [write_diagnostic].
+pub(crate) fn print_diagnostic(severity: Severity, message: &str, source: &str, span: &Span) {
+    write_diagnostic(std::io::stderr(), severity, message, source, span)
+        .expect("failed printing to stderr");
+}
+
+/// Write a problem meant for stderr, pointing at where it is in `source`, e.g.
+///
+/// ```text
+/// warning: Opening brace is never closed
+///  --> main.c:3:12
+///   |
+/// 3 | int main() {
+///   |            ^
+/// ```
+pub(crate) fn write_diagnostic(
+    mut out: impl Write,
+    severity: Severity,
+    message: &str,
+    source: &str,
+    span: &Span,
+) -> std::io::Result<()> {
+    let label = match severity {
+        Severity::Warning => style_e("warning").yellow(),
+        Severity::Error => style_e("error").red(),
+    };
+    let line_number = span.line.to_string();
+    let gutter = " ".repeat(line_number.len());
+    let bar = style_e("|").blue().bold();
+    // Keep tabs so the carets line up with the snippet
+    let caret_indent = span
+        .snippet
+        .chars()
+        .take(span.column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect::<String>();
+    let carets = "^".repeat(span.len);
+    let carets = match severity {
+        Severity::Warning => style_e(carets).yellow(),
+        Severity::Error => style_e(carets).red(),
+    };
+
+    writeln!(out, "{}: {}", label.bold(), style_e(message).bold())?;
+    writeln!(
+        out,
+        "{}{} {}:{}:{}",
+        gutter,
+        style_e("-->").blue().bold(),
+        source,
+        span.line,
+        span.column
+    )?;
+    writeln!(out, "{} {}", gutter, bar)?;
+    writeln!(
+        out,
+        "{} {} {}",
+        style_e(line_number).blue().bold(),
+        bar,
+        span.snippet
+    )?;
+    writeln!(out, "{} {} {}{}", gutter, bar, caret_indent, carets.bold())
+}
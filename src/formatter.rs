@@ -1,106 +1,1277 @@
-use once_cell::sync::Lazy;
-use regex::Regex;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::str::FromStr;
+
 use thiserror::Error;
 
+use crate::formatter::lexer::{is_word_char, Kind, Lexer};
+use crate::language::Language;
+
+mod lexer;
+
 #[derive(Error, Debug)]
-pub enum Error {}
+pub enum Error {
+    #[error("Input is not valid UTF-8")]
+    InvalidUtf8 { span: Span },
+}
+
+impl Error {
+    pub fn span(&self) -> &Span {
+        match self {
+            Error::InvalidUtf8 { span } => span,
+        }
+    }
+}
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Something worth pointing out about the code, that didn't stop it from being formatted.
+#[derive(Error, Debug)]
+pub enum Warning {
+    #[error("Code is {width} columns wide, reaching past the junk column {column}")]
+    Overflow {
+        span: Span,
+        width: usize,
+        column: usize,
+    },
+    #[error("Junk can't safely be moved onto the previous line, so it was left here")]
+    UnmovableJunk { span: Span },
+    #[error("Closing brace has no matching opening brace")]
+    UnmatchedBrace { span: Span },
+    #[error("Opening brace is never closed")]
+    UnclosedBrace { span: Span },
+    #[error("{message}, so this setting was ignored")]
+    InvalidSetting { span: Span, message: String },
+}
+
+impl Warning {
+    pub fn span(&self) -> &Span {
+        match self {
+            Warning::Overflow { span, .. }
+            | Warning::UnmovableJunk { span }
+            | Warning::UnmatchedBrace { span }
+            | Warning::UnclosedBrace { span }
+            | Warning::InvalidSetting { span, .. } => span,
+        }
+    }
+}
+
+/// Where a problem is in the input.
+#[derive(Clone, Debug)]
+pub struct Span {
+    /// Counted from 1
+    pub line: usize,
+    /// Counted from 1, in characters
+    pub column: usize,
+    /// In characters, at least 1
+    pub len: usize,
+    /// The line the problem is on, without its terminator
+    pub snippet: String,
+}
+
+impl Span {
+    fn new(number: usize, text: &str, range: Range<usize>) -> Self {
+        Span {
+            line: number + 1,
+            column: text[..range.start].chars().count() + 1,
+            len: text[range].chars().count().max(1),
+            snippet: text.to_string(),
+        }
+    }
+}
+
+/// Turn raw input into text to format, pointing out where it stops being UTF-8 if it isn't.
+pub fn decode(bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|e| {
+        let bytes = e.as_bytes();
+        let valid_up_to = e.utf8_error().valid_up_to();
+        let line_start = bytes[..valid_up_to]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |idx| idx + 1);
+        let line_end = bytes[valid_up_to..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(bytes.len(), |idx| valid_up_to + idx);
+        let number = bytes[..line_start].iter().filter(|&&b| b == b'\n').count();
+        let text = String::from_utf8_lossy(&bytes[line_start..line_end]);
+        let text = text.trim_end_matches('\r');
+        let column = valid_up_to - line_start;
+        // Everything before the bad byte is valid, so the lossy text lines up until then
+        Error::InvalidUtf8 {
+            span: Span::new(number, text, column..column),
+        }
+    })
+}
+
+/// Where to put the junk on each line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JunkColumn {
+    Fixed(usize),
+    /// Just past the widest code in the file, `padding` columns after it, but never past `max`
+    Auto {
+        padding: usize,
+        max: Option<usize>,
+    },
+}
+
+impl From<usize> for JunkColumn {
+    fn from(column: usize) -> Self {
+        JunkColumn::Fixed(column)
+    }
+}
+
+#[derive(Error, Debug)]
+#[error(
+    "Invalid junk column '{0}', expected a number, 'auto' or 'auto:<padding>[:<max>]' with a \
+     padding of at least 1"
+)]
+pub struct InvalidJunkColumn(String);
+
+impl FromStr for JunkColumn {
+    type Err = InvalidJunkColumn;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || InvalidJunkColumn(s.to_string());
+        let mut parts = s.split(':');
+        let first = parts.next().unwrap_or_default();
+        if !first.eq_ignore_ascii_case("auto") {
+            return s.parse().map(JunkColumn::Fixed).map_err(|_| invalid());
+        }
+        let mut numbers = parts.map(|part| part.parse().map_err(|_| invalid()));
+        let padding = numbers.next().transpose()?.unwrap_or(1);
+        // The widest line would have its junk right against the code, where it isn't aligned
+        if padding == 0 {
+            return Err(invalid());
+        }
+        let max = numbers.next().transpose()?;
+        if numbers.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(JunkColumn::Auto { padding, max })
+    }
+}
+
+/// Which lines share a junk column, when it is computed automatically.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Grouping {
+    /// Every line in the file
+    File,
+    /// Each run of non-blank lines
+    Paragraph,
+    /// Each brace block, such as a C function, and each run of non-blank lines between them.
+    /// Blocks are taken from the outermost level holding more than one, so in a Java class they
+    /// are its methods
+    Block,
+}
+
+#[derive(Error, Debug)]
+#[error("Invalid grouping '{0}', expected 'file', 'paragraph' or 'block'")]
+pub struct InvalidGrouping(String);
+
+impl FromStr for Grouping {
+    type Err = InvalidGrouping;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "file" => Ok(Grouping::File),
+            "paragraph" => Ok(Grouping::Paragraph),
+            "block" => Ok(Grouping::Block),
+            _ => Err(InvalidGrouping(s.to_string())),
+        }
+    }
+}
+
+/// Where to put junk on lines whose code already reaches the junk column.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Put it this many spaces after the code
+    Gap(usize),
+    /// Put it on the next multiple of this width past the junk column
+    TabStop(usize),
+}
+
+#[derive(Error, Debug)]
+#[error("Invalid overflow policy '{0}', expected 'gap:<spaces>' or 'tab:<width>'")]
+pub struct InvalidOverflow(String);
+
+impl FromStr for Overflow {
+    type Err = InvalidOverflow;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || InvalidOverflow(s.to_string());
+        let (policy, amount) = s.split_once(':').ok_or_else(invalid)?;
+        let amount = amount.parse().map_err(|_| invalid())?;
+        match policy.to_ascii_lowercase().as_str() {
+            "gap" => Ok(Overflow::Gap(amount)),
+            "tab" if amount > 0 => Ok(Overflow::TabStop(amount)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Which line terminators to write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NewlineStyle {
+    /// Keep each line's terminator, the final newline (or lack of one) and any byte order mark
+    Preserve,
+    /// Use `\n` everywhere, always end with a newline, and drop any byte order mark
+    Unix,
+    /// Use `\r\n` everywhere, always end with a newline, and drop any byte order mark
+    Windows,
+}
+
+#[derive(Error, Debug)]
+#[error("Invalid newline style '{0}', expected 'preserve', 'unix' or 'windows'")]
+pub struct InvalidNewlineStyle(String);
+
+impl FromStr for NewlineStyle {
+    type Err = InvalidNewlineStyle;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "preserve" => Ok(NewlineStyle::Preserve),
+            "unix" => Ok(NewlineStyle::Unix),
+            "windows" => Ok(NewlineStyle::Windows),
+            _ => Err(InvalidNewlineStyle(s.to_string())),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Formatter {
-    junk_column: usize,
-}
-
-// Note: we do set ops on this string, so it should stay small
-// If it gets too large for some reason, it might be worth making a set
-const JUNK_CHARS: &str = "{};";
-static WS_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new("\\s").unwrap());
-static BASE_JUNK_WS_REGEX: Lazy<String> =
-    Lazy::new(|| format!("[{}{}]", "\\s", regex::escape(JUNK_CHARS)));
-// Matches junk and whitespace from the start of the string
-static START_JUNK_WS_REGEX: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(&*format!(
-        "^\\s+([{}]{}*)",
-        regex::escape(JUNK_CHARS),
-        &*BASE_JUNK_WS_REGEX,
-    ))
-    .unwrap()
-});
-// Matches junk and whitespace from the end of the string
-static END_JUNK_WS_REGEX: Lazy<Regex> =
-    Lazy::new(|| Regex::new(&*format!("{}+$", &*BASE_JUNK_WS_REGEX)).unwrap());
+    junk_column: JunkColumn,
+    grouping: Grouping,
+    overflow: Overflow,
+    warn_overflow: bool,
+    newline_style: NewlineStyle,
+    // Overrides the language's junk characters when set
+    junk_chars: Option<String>,
+    // Where trailing comments go after the junk, if not just where they were
+    comment_column: Option<usize>,
+    language: Language,
+}
+
+/// A line of source, along with what each of its bytes lexically belongs to.
+struct Line {
+    // Where the line was in the input, from 0
+    number: usize,
+    text: String,
+    // The text as it was in the input, which is what problems get pointed out in
+    original: String,
+    // Where junk was taken from the start of the text, so it can be lined up with the original
+    hoisted: Range<usize>,
+    // Where code from later lines was added, before any was taken from the start
+    pushed: Range<usize>,
+    kinds: Vec<Kind>,
+    // What the line ends inside of, which is only code if more code can go after it
+    ends_in: Kind,
+    ending: &'static str,
+    // Whether a directive asked for this line to be left exactly as it is
+    verbatim: bool,
+    // Whether this is a preprocessor directive, or a continuation of one
+    preprocessor: bool,
+    // Whether the code and its trailing junk are spaced apart the other way from how reversing
+    // would guess, which aligning records with a space more in front of the junk
+    gap_flipped: bool,
+}
+
+/// A `pythonicfmt: ...` comment controlling which lines get formatted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Directive {
+    /// Leave lines alone until the next `on`
+    Off,
+    On,
+    /// Leave the next line alone
+    Skip,
+}
+
+impl Line {
+    fn is_code(&self, idx: usize) -> bool {
+        self.kinds[idx] == Kind::Code
+    }
+
+    // Note: we do set ops on the junk string, so it should stay small
+    fn is_junk_or_ws(&self, junk: &str, idx: usize, c: char) -> bool {
+        self.is_code(idx) && (c.is_whitespace() || junk.contains(c))
+    }
+
+    // Finds the junk (and interleaved whitespace) following the indentation
+    fn leading_junk(&self, junk: &str) -> Option<Range<usize>> {
+        let mut chars = self.text.char_indices().peekable();
+        let mut start = None;
+        while let Some(&(idx, c)) = chars.peek() {
+            if !self.is_code(idx) || !c.is_whitespace() {
+                start = Some(idx);
+                break;
+            }
+            chars.next();
+        }
+        let start = start.filter(|&start| start > 0)?;
+        if !self.is_junk_start(junk, start) {
+            return None;
+        }
+        let end = chars
+            .find(|&(idx, c)| !self.is_junk_or_ws(junk, idx, c))
+            .map_or(self.text.len(), |(idx, _)| idx);
+        Some(start..end)
+    }
+
+    fn is_junk_start(&self, junk: &str, idx: usize) -> bool {
+        self.text[idx..]
+            .chars()
+            .next()
+            .is_some_and(|c| self.is_code(idx) && junk.contains(c))
+    }
+
+    // Finds the junk (and interleaved whitespace) at the end of the code, before any trailing
+    // comment
+    fn trailing_junk(&self, junk: &str) -> Option<Range<usize>> {
+        let end = self.code_end();
+        let start = self.text[..end]
+            .char_indices()
+            .rev()
+            .take_while(|&(idx, c)| self.is_junk_or_ws(junk, idx, c))
+            .last()
+            .map(|(idx, _)| idx)?;
+        Some(start..end)
+    }
+
+    // How wide the line is without its trailing junk and comment
+    fn code_width(&self, junk: &str) -> usize {
+        let end = self
+            .trailing_junk(junk)
+            .map_or(self.code_end(), |range| range.start);
+        self.text[..end].chars().count()
+    }
+
+    // Finds the comments (and interleaved whitespace) ending a line that has code before them
+    fn trailing_comment(&self) -> Option<Range<usize>> {
+        if self.ends_in != Kind::Code && self.ends_in != Kind::LineComment {
+            return None;
+        }
+        let start = self
+            .text
+            .char_indices()
+            .rev()
+            .take_while(|&(idx, c)| match self.kinds[idx] {
+                Kind::Code => c.is_whitespace(),
+                Kind::LineComment | Kind::BlockComment => true,
+                Kind::String | Kind::Char => false,
+            })
+            .last()
+            .map(|(idx, _)| idx)?;
+        let rest = &self.text[start..];
+        let start = start + rest.len() - rest.trim_start().len();
+        let has_code = !self.text[..start].trim().is_empty();
+        Some(start..self.text.len()).filter(|range| has_code && !range.is_empty())
+    }
+
+    // Where code ends, before any trailing comment and the whitespace leading up to it
+    fn code_end(&self) -> usize {
+        match self.trailing_comment() {
+            Some(range) => self.text[..range.start].trim_end().len(),
+            None => self.text.len(),
+        }
+    }
+
+    fn is_junk_only(&self, junk: &str) -> bool {
+        self.trailing_junk(junk)
+            .is_some_and(|range| range == (0..self.text.len()))
+            && self.text.chars().any(|c| !c.is_whitespace())
+    }
+
+    // Whether the line holds nothing but whitespace and comments
+    fn is_blank_or_comment(&self) -> bool {
+        self.text
+            .char_indices()
+            .all(|(idx, c)| match self.kinds[idx] {
+                Kind::Code => c.is_whitespace(),
+                Kind::LineComment | Kind::BlockComment => true,
+                Kind::String | Kind::Char => false,
+            })
+    }
+
+    fn replace_range(&mut self, range: Range<usize>, code: &str) {
+        self.kinds
+            .splice(range.clone(), std::iter::repeat_n(Kind::Code, code.len()));
+        self.text.replace_range(range, code);
+    }
+
+    fn span(&self, range: Range<usize>) -> Span {
+        let original = |idx: usize| {
+            let idx = if idx >= self.hoisted.start {
+                idx + self.hoisted.len()
+            } else {
+                idx
+            };
+            if idx >= self.pushed.end {
+                idx - self.pushed.len()
+            } else {
+                idx.min(self.pushed.start)
+            }
+        };
+        Span::new(
+            self.number,
+            &self.original,
+            original(range.start)..original(range.end),
+        )
+    }
+
+    fn can_push_code(&self) -> bool {
+        // Code after a line continuation would end up before the newline it escapes
+        let continued = self.text[..self.code_end()].ends_with('\\');
+        !self.verbatim
+            && !continued
+            && (self.ends_in == Kind::Code || self.trailing_comment().is_some())
+    }
+
+    fn directive(&self) -> Option<Directive> {
+        let start = self
+            .kinds
+            .iter()
+            .position(|&kind| kind == Kind::LineComment || kind == Kind::BlockComment)?;
+        let comment = self.text[start..]
+            .trim_start_matches(['/', '*', '#'])
+            .trim_end()
+            .trim_end_matches("*/");
+        match comment.trim().strip_prefix("pythonicfmt:")?.trim() {
+            "off" => Some(Directive::Off),
+            "on" => Some(Directive::On),
+            "skip" => Some(Directive::Skip),
+            _ => None,
+        }
+    }
+
+    // Adds code to the end of the line, keeping any trailing comment after it
+    fn push_code(&mut self, code: &str) {
+        let end = self.code_end();
+        self.replace_range(end..end, code);
+        // Code only ever gets added right after what was added before
+        let start = if self.pushed.is_empty() {
+            end
+        } else {
+            self.pushed.start
+        };
+        self.pushed = start..end + code.len();
+    }
+
+    // Adds junk moved up from a later line, with a space in front for each blank and comment-only
+    // line it was moved over, so reversing can put it back after them. A closing brace for one
+    // opened on this line gets two more, or it would pass for one that was always here
+    fn push_hoisted(&mut self, junk: &str, skipped: usize, junk_chars: &str) {
+        let junk = strip_whitespace(junk);
+        let end = self.code_end();
+        let start = self
+            .leading_junk(junk_chars)
+            .map_or(0, |range| range.end.min(end));
+        let closes_here = junk.starts_with('}') && self.unclosed_brace(start..end).is_some();
+        let spaces = skipped + if closes_here { 2 } else { 0 };
+        self.push_code(&format!("{}{}", " ".repeat(spaces), junk));
+    }
+
+    // Where the last brace opened within `range` and not closed within it is
+    fn unclosed_brace(&self, range: Range<usize>) -> Option<usize> {
+        let mut open = Vec::new();
+        for (idx, c) in self.text[range.clone()].char_indices() {
+            match c {
+                '{' if self.is_code(range.start + idx) => open.push(range.start + idx),
+                '}' if self.is_code(range.start + idx) => {
+                    open.pop();
+                }
+                _ => {}
+            }
+        }
+        open.pop()
+    }
+
+    // Whether reversing puts whitespace between the code and the junk starting at `junk_start`,
+    // unless told otherwise, or None if the junk goes on a line of its own anyway
+    fn guessed_gap(&self, junk_start: usize) -> Option<bool> {
+        match self.text[junk_start..].trim_start().chars().next()? {
+            '{' => Some(true),
+            '}' => {
+                let brace = self.unclosed_brace(0..junk_start)?;
+                Some(self.text[brace + 1..].starts_with(char::is_whitespace))
+            }
+            _ => Some(false),
+        }
+    }
+
+    fn is_gap_flipped(&self, junk: &str) -> bool {
+        match self.trailing_junk(junk) {
+            Some(range) if !self.text[..range.start].trim().is_empty() => {
+                let spaced = self.text[range.start..].starts_with(char::is_whitespace);
+                self.guessed_gap(range.start)
+                    .is_some_and(|guess| guess != spaced)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// What surrounds the lines of the input, so it can be put back afterwards.
+struct Layout {
+    bom: bool,
+    // The terminator of the last line, empty if there is no final newline
+    final_ending: &'static str,
+}
+
+fn split_ending(line: &str) -> (&str, &'static str) {
+    if let Some(text) = line.strip_suffix("\r\n") {
+        (text, "\r\n")
+    } else if let Some(text) = line.strip_suffix('\n') {
+        (text, "\n")
+    } else {
+        (line, "")
+    }
+}
+
+// Finds the line to move junk from `lines[idx]` onto, skipping back over blank and comment-only
+// lines so they stay where they are
+fn hoist_target(lines: &[Line], idx: usize) -> Option<usize> {
+    let target = lines[..idx]
+        .iter()
+        .rposition(|line| line.verbatim || !line.is_blank_or_comment())?;
+    Some(target).filter(|&target| lines[target].can_push_code())
+}
+
+// Applies the directives, directive lines themselves included, and keeps preprocessor lines as
+// they are, since moving junk in or out of them changes what gets compiled
+fn mark_verbatim(lines: &mut [Line]) {
+    let mut off = false;
+    let mut skip_next = false;
+    for line in lines {
+        let directive = line.directive();
+        line.verbatim = off || skip_next || directive.is_some() || line.preprocessor;
+        skip_next = directive == Some(Directive::Skip);
+        match directive {
+            Some(Directive::Off) => off = true,
+            Some(Directive::On) => off = false,
+            _ => {}
+        }
+    }
+}
+
+fn check_braces(lines: &[Line]) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    let mut open = Vec::new();
+    // Macros and conditional compilation can't be expected to balance
+    for line in lines.iter().filter(|line| !line.preprocessor) {
+        for (idx, c) in line.text.char_indices() {
+            match c {
+                '{' if line.is_code(idx) => open.push(line.span(idx..idx + 1)),
+                '}' if line.is_code(idx) && open.pop().is_none() => {
+                    warnings.push(Warning::UnmatchedBrace {
+                        span: line.span(idx..idx + 1),
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+    warnings.extend(open.into_iter().map(|span| Warning::UnclosedBrace { span }));
+    warnings
+}
+
+/// A brace that is still open, while reversing.
+#[derive(Default)]
+struct Opener {
+    // The indentation of the statement that opened it, which the line closing it gets too
+    indent: String,
+    // Whether it opens the body of a `do`, whose `while` goes after the closing brace
+    is_do: bool,
+}
+
+/// A line split back out of the junk, while reversing.
+struct Piece {
+    text: String,
+    ending: &'static str,
+    // How many blank and comment-only lines it was moved up over
+    skipped: usize,
+    closes_do: bool,
+}
+
+impl Piece {
+    fn line(self) -> (String, &'static str) {
+        (self.text, self.ending)
+    }
+}
+
+// Keywords that continue the statement before them when they follow a closing brace
+const AFTER_BRACE: &[&str] = &["else", "catch", "finally"];
+
+// Puts a closing brace back in front of what followed it on the same line, like `} else {`
+fn joined(brace: &Piece, next: &str) -> Option<String> {
+    let indent = indent_of(&brace.text);
+    let rest = next.strip_prefix(indent)?;
+    if brace.text.trim() != "}" || rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let starts_with_word = |word: &str| {
+        rest.strip_prefix(word)
+            .is_some_and(|after| !after.starts_with(is_word_char))
+    };
+    let separator = if AFTER_BRACE.iter().any(|&word| starts_with_word(word))
+        || (brace.closes_do && starts_with_word("while"))
+    {
+        " "
+    } else if rest.starts_with([')', ']', ',']) {
+        ""
+    } else {
+        return None;
+    };
+    Some(format!("{}{}{}", brace.text, separator, rest))
+}
+
+fn indent_of(text: &str) -> &str {
+    &text[..text.len() - text.trim_start().len()]
+}
+
+fn strip_whitespace(s: &str) -> String {
+    s.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+// Each whitespace-separated word of `s`, along with where it starts
+fn words(s: &str) -> impl Iterator<Item = (usize, &str)> {
+    s.split_inclusive(char::is_whitespace)
+        .scan(0, |start, piece| {
+            let word = piece.strip_suffix(char::is_whitespace).unwrap_or(piece);
+            let item = (*start, word);
+            *start += piece.len();
+            Some(item)
+        })
+        .filter(|(_, word)| !word.is_empty())
+}
+
+fn parse_setting<T>(value: &str) -> std::result::Result<T, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    value.parse().map_err(|e: T::Err| e.to_string())
+}
+
+// How far into a file to look for a modeline
+const MODELINE_LINES: usize = 5;
+const MODELINE_PREFIX: &str = "pythonicfmt:";
 
 impl Formatter {
-    pub fn junk_column(&mut self, junk_column: usize) -> &mut Self {
-        self.junk_column = junk_column;
+    pub fn junk_column(&mut self, junk_column: impl Into<JunkColumn>) -> &mut Self {
+        self.junk_column = junk_column.into();
+        self
+    }
+
+    /// Set which lines are aligned together when using [JunkColumn::Auto].
+    pub fn grouping(&mut self, grouping: Grouping) -> &mut Self {
+        self.grouping = grouping;
+        self
+    }
+
+    /// Set where junk goes when the code already reaches the junk column.
+    pub fn overflow(&mut self, overflow: Overflow) -> &mut Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Set whether to warn about lines whose code reaches the junk column.
+    pub fn warn_overflow(&mut self, warn_overflow: bool) -> &mut Self {
+        self.warn_overflow = warn_overflow;
+        self
+    }
+
+    pub fn newline_style(&mut self, newline_style: NewlineStyle) -> &mut Self {
+        self.newline_style = newline_style;
+        self
+    }
+
+    /// Set the characters to treat as junk, instead of the ones for the language.
+    ///
+    /// Whitespace is ignored.
+    pub fn junk_chars(&mut self, junk_chars: &str) -> &mut Self {
+        self.junk_chars = Some(strip_whitespace(junk_chars));
+        self
+    }
+
+    /// Set the column to line up trailing comments at, after the junk, instead of keeping the
+    /// space they had before it.
+    pub fn comment_column(&mut self, comment_column: usize) -> &mut Self {
+        self.comment_column = Some(comment_column);
+        self
+    }
+
+    pub fn language(&mut self, language: Language) -> &mut Self {
+        self.language = language;
         self
     }
 
+    /// Apply the settings from a modeline comment near the top of `content`, if it has one,
+    /// e.g. `// pythonicfmt: junk-column=96 junk-chars={};`.
+    fn with_modeline(&self, content: &str) -> (Cow<'_, Formatter>, Vec<Warning>) {
+        let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+        let mut lexer = Lexer::new(self.language.syntax());
+        for (number, line) in content
+            .split_inclusive('\n')
+            .take(MODELINE_LINES)
+            .enumerate()
+        {
+            let (text, _) = split_ending(line);
+            let kinds = lexer.lex_line(text);
+            let start = match text.find(MODELINE_PREFIX) {
+                Some(start) if matches!(kinds[start], Kind::LineComment | Kind::BlockComment) => {
+                    start + MODELINE_PREFIX.len()
+                }
+                _ => continue,
+            };
+            let settings = text[start..].trim_end().trim_end_matches("*/");
+            // Otherwise it's a directive
+            if !settings.contains('=') {
+                continue;
+            }
+            let mut formatter = self.clone();
+            let mut warnings = Vec::new();
+            for (offset, setting) in words(settings) {
+                if let Err(message) = formatter.apply_setting(setting) {
+                    let range = start + offset..start + offset + setting.len();
+                    warnings.push(Warning::InvalidSetting {
+                        span: Span::new(number, text, range),
+                        message,
+                    });
+                }
+            }
+            return (Cow::Owned(formatter), warnings);
+        }
+        (Cow::Borrowed(self), Vec::new())
+    }
+
+    // Applies one `key=value` from a modeline, named like the command line options
+    fn apply_setting(&mut self, setting: &str) -> std::result::Result<(), String> {
+        let (key, value) = setting
+            .split_once('=')
+            .ok_or_else(|| format!("Expected 'key=value', found '{}'", setting))?;
+        match key {
+            "junk-column" => self.junk_column(parse_setting::<JunkColumn>(value)?),
+            "grouping" => self.grouping(parse_setting(value)?),
+            "overflow" => self.overflow(parse_setting(value)?),
+            "warn-overflow" => self.warn_overflow(parse_setting(value)?),
+            "junk-chars" => self.junk_chars(value),
+            "comment-column" => self.comment_column(parse_setting(value)?),
+            "language" => self.language(parse_setting(value)?),
+            "newline-style" => self.newline_style(parse_setting(value)?),
+            _ => return Err(format!("Unknown setting '{}'", key)),
+        };
+        Ok(())
+    }
+
+    /// Format `content`, returning anything worth pointing out about it.
+    ///
+    /// A `pythonicfmt: key=value ...` comment in the first few lines changes the settings for
+    /// just this content, using the names of the command line options.
+    pub fn format(&self, content: &mut String) -> Result<Vec<Warning>> {
+        let (formatter, warnings) = self.with_modeline(content);
+        formatter.format_with(content, warnings)
+    }
+
     // Thanks to &mut String, this could be optimized later
     // For now I'll be super un-optimal :)
-    pub fn format(&self, content: &mut String) -> Result<()> {
-        let mut lines = content
-            .lines()
-            .map(|line| line.to_string())
-            .collect::<Vec<_>>();
-        self.collapse_lines(&mut lines);
+    fn format_with(
+        &self,
+        content: &mut String,
+        mut warnings: Vec<Warning>,
+    ) -> Result<Vec<Warning>> {
+        let (layout, mut lines) = self.split_lines(content);
+        warnings.extend(check_braces(&lines));
+        for line in &mut lines {
+            line.gap_flipped = line.is_gap_flipped(self.junk());
+        }
+        self.collapse_lines(&mut lines, &mut warnings);
         // Iterate backwards so all start-of-line is resolved prior to end-of-line
-        let mut idx = lines.len() - 1;
-        loop {
-            self.process_line(&mut lines, idx);
-            if idx == 0 {
-                break;
+        for idx in (0..lines.len()).rev() {
+            warnings.extend(self.process_line(&mut lines, idx));
+        }
+        for group in self.alignment_groups(&lines) {
+            let junk_column = self.resolve_junk_column(&lines[group.clone()]);
+            for line in &mut lines[group] {
+                let warning = self.align_junk(line, junk_column);
+                warnings.extend(warning.filter(|_| self.warn_overflow));
             }
-            idx -= 1;
         }
 
-        *content = lines.join("\n");
+        *content = self.join_lines(
+            &layout,
+            lines.into_iter().map(|line| (line.text, line.ending)),
+        );
+
+        Ok(warnings)
+    }
+
+    /// Undo [Formatter::format], pulling junk back next to the code it belongs to.
+    ///
+    /// Runs of closing braces are split back onto their own lines, indented to match the
+    /// statement that opened them, and after any blank lines they were moved up over. Junk that
+    /// was never aligned, like in code that was never formatted, is left as it is.
+    ///
+    /// The settings need to be the ones the content was formatted with, since where the junk was
+    /// put says how it was spaced apart from the code.
+    pub fn unformat(&self, content: &mut String) -> Result<()> {
+        // Any problems with the modeline get pointed out when formatting
+        let (formatter, _) = self.with_modeline(content);
+        formatter.unformat_with(content)
+    }
+
+    fn unformat_with(&self, content: &mut String) -> Result<()> {
+        let mut output = Vec::new();
+        let mut open = Vec::new();
+        // Closing braces split out of the last line of code, and how many blank and comment-only
+        // lines have gone by since
+        let mut held = VecDeque::new();
+        let mut waited = 0;
+        // Braces get closed at the indentation of the line their statement started on
+        let mut statement_indent = String::new();
+        let mut in_statement = false;
+        let (layout, lines) = self.split_lines(content);
+        // Where formatting would have put each line's junk, to tell when it was put a space further
+        let mut junk_columns = vec![0; lines.len()];
+        for group in self.alignment_groups(&lines) {
+            let junk_column = self.resolve_junk_column(&lines[group.clone()]);
+            junk_columns[group].fill(junk_column);
+        }
+        // Lines split out of the last one still need terminating, even without a final newline
+        let mut ending = "\n";
+        for (line, junk_column) in lines.into_iter().zip(junk_columns) {
+            if !line.ending.is_empty() {
+                ending = line.ending;
+            }
+            let mut before = None;
+            while held
+                .front()
+                .is_some_and(|piece: &Piece| piece.skipped <= waited)
+            {
+                output.extend(before.replace(held.pop_front().unwrap()).map(Piece::line));
+            }
+            if !held.is_empty() && !line.verbatim && line.is_blank_or_comment() {
+                output.extend(before.map(Piece::line));
+                output.push((line.text, ending));
+                waited += 1;
+                continue;
+            }
+            // Anything still waiting has run out of lines to wait for
+            while let Some(piece) = held.pop_front() {
+                output.extend(before.replace(piece).map(Piece::line));
+            }
+            waited = 0;
+
+            if !in_statement {
+                statement_indent = indent_of(&line.text).to_string();
+            }
+            if !line.is_blank_or_comment() {
+                let code = line.text[..line.code_end()].trim_end();
+                in_statement = !line.preprocessor && !code.ends_with([';', '{', '}']);
+            }
+            let (mut text, pieces) =
+                self.unformat_line(&line, &statement_indent, junk_column, ending, &mut open);
+            if let Some(before) = before {
+                match joined(&before, &text).filter(|_| !line.verbatim) {
+                    Some(joined) => text = joined,
+                    None => output.push(before.line()),
+                }
+            }
+            output.push((text, ending));
+            held.extend(pieces);
+        }
+        output.extend(held.into_iter().map(Piece::line));
+
+        *content = self.join_lines(&layout, output);
 
         Ok(())
     }
 
-    fn process_line(&self, lines: &mut Vec<String>, idx: usize) {
-        let mut prev_line_modification: Option<String> = None;
+    // Pulls the junk on `line` back next to its code, returning what's left of the line and the
+    // closing braces that go on lines of their own after it
+    fn unformat_line(
+        &self,
+        line: &Line,
+        indent: &str,
+        junk_column: usize,
+        ending: &'static str,
+        open: &mut Vec<Opener>,
+    ) -> (String, Vec<Piece>) {
+        let is_do = line
+            .text
+            .trim_start()
+            .strip_prefix("do")
+            .is_some_and(|rest| !rest.starts_with(is_word_char));
+        let opener = || Opener {
+            indent: indent.to_string(),
+            is_do,
+        };
+        let junk_range = line
+            .trailing_junk(self.junk())
+            .filter(|_| !line.verbatim)
+            .unwrap_or(line.text.len()..line.text.len());
+        let junk_start = junk_range.start;
+        // Where this line opens braces it hasn't closed yet, if not in the junk
+        let mut opened_here: Vec<Option<usize>> = Vec::new();
+        for (idx, c) in line.text[..junk_start].char_indices() {
+            match c {
+                '{' if line.is_code(idx) => {
+                    open.push(opener());
+                    opened_here.push(Some(idx));
+                }
+                '}' if line.is_code(idx) => {
+                    open.pop();
+                    opened_here.pop();
+                }
+                _ => {}
+            }
+        }
+        let junk = line.text[junk_range.clone()].trim();
+        let code = &line.text[..junk_start];
+        // Junk on its own line was either always there or couldn't be moved, and junk right after
+        // the code was never aligned, so either way it stays put
+        let aligned = line.text[junk_start..].starts_with(char::is_whitespace);
+        if junk.is_empty() || code.trim().is_empty() || !aligned {
+            for c in junk.chars() {
+                match c {
+                    '{' => open.push(opener()),
+                    '}' => {
+                        open.pop();
+                    }
+                    _ => {}
+                }
+            }
+            return (line.text.clone(), Vec::new());
+        }
+
+        // Junk a space further than aligning puts it was spaced apart from the code the other
+        // way from how it's guessed
+        let width = code.chars().count();
+        let junk_text = &line.text[junk_range.clone()];
+        let junk_at = width + junk_text.len() - junk_text.trim_start().len();
+        let flipped = junk_at == width + self.junk_gap(width, junk_column) + 1;
+        let separator = match line.guessed_gap(junk_start) {
+            Some(gap) if gap != flipped => " ",
+            _ => "",
+        };
+        let mut pieces = vec![Piece {
+            text: format!("{}{}", code, separator),
+            ending,
+            skipped: 0,
+            closes_do: false,
+        }];
+        // A closing brace goes back on a line of its own, taking any junk after it along, unless
+        // it closes a brace opened on this line and has fewer than two spaces in front. Any
+        // other spaces in front of it say how many lines it was moved up over
+        let mut spaces = 0;
+        for c in junk.chars() {
+            if c.is_whitespace() {
+                spaces += 1;
+                continue;
+            }
+            let starts_piece = match c {
+                '}' => {
+                    let opener = open.pop().unwrap_or_default();
+                    let closes_here = opened_here.pop().is_some();
+                    if !closes_here {
+                        Some(opener)
+                    } else if spaces >= 2 {
+                        spaces -= 2;
+                        Some(opener)
+                    } else {
+                        None
+                    }
+                }
+                // Nothing goes between a closing brace and an opening one but its own line
+                '{' if pieces.len() > 1 => {
+                    let piece = pieces.last().expect("There is always a piece");
+                    let indent = indent_of(&piece.text).to_string();
+                    open.push(Opener {
+                        indent: indent.clone(),
+                        is_do: false,
+                    });
+                    opened_here.push(None);
+                    Some(Opener {
+                        indent,
+                        is_do: false,
+                    })
+                }
+                '{' => {
+                    open.push(opener());
+                    opened_here.push(None);
+                    None
+                }
+                _ => None,
+            };
+            if let Some(opener) = starts_piece {
+                pieces.push(Piece {
+                    text: opener.indent,
+                    ending,
+                    skipped: std::mem::take(&mut spaces),
+                    closes_do: opener.is_do,
+                });
+            }
+            let piece = pieces.last_mut().expect("There is always a piece");
+            piece
+                .text
+                .extend(std::iter::repeat_n(' ', std::mem::take(&mut spaces)));
+            piece.text.push(c);
+        }
+        let mut pieces = pieces.into_iter();
+        let first = pieces.next().expect("There is always a piece");
+        // Any trailing comment goes back on the first line made from this one
+        let text = format!("{}{}", first.text, &line.text[junk_range.end..]);
+        (text, pieces.collect())
+    }
+
+    fn split_lines(&self, content: &str) -> (Layout, Vec<Line>) {
+        let (bom, content) = match content.strip_prefix('\u{feff}') {
+            Some(content) => (true, content),
+            None => (false, content),
+        };
+        let syntax = self.language.syntax();
+        let mut lexer = Lexer::new(syntax);
+        let mut continues_directive = false;
+        let mut lines = content
+            .split_inclusive('\n')
+            .enumerate()
+            .map(|(number, line)| {
+                let (text, ending) = split_ending(line);
+                let kinds = lexer.lex_line(text);
+                let ends_in = match kinds.last() {
+                    Some(Kind::LineComment) => Kind::LineComment,
+                    _ => lexer.kind(),
+                };
+                let indent = text.len() - text.trim_start().len();
+                let preprocessor = syntax.preprocessor
+                    && (continues_directive
+                        || (text[indent..].starts_with('#') && kinds[indent] == Kind::Code));
+                continues_directive = preprocessor && text.ends_with('\\');
+                Line {
+                    number,
+                    text: text.to_string(),
+                    original: text.to_string(),
+                    hoisted: 0..0,
+                    pushed: 0..0,
+                    kinds,
+                    ends_in,
+                    ending,
+                    verbatim: false,
+                    preprocessor,
+                    gap_flipped: false,
+                }
+            })
+            .collect::<Vec<_>>();
+        mark_verbatim(&mut lines);
+        let final_ending = lines.last().map_or("", |line| line.ending);
+        (Layout { bom, final_ending }, lines)
+    }
+
+    fn join_lines(
+        &self,
+        layout: &Layout,
+        lines: impl IntoIterator<Item = (String, &'static str)>,
+    ) -> String {
+        let mut output = String::new();
+        if layout.bom && self.newline_style == NewlineStyle::Preserve {
+            output.push('\u{feff}');
+        }
+        let mut lines = lines.into_iter().peekable();
+        while let Some((text, ending)) = lines.next() {
+            output += &text;
+            output += match self.newline_style {
+                // The last line may have changed, but the file should still end the same way
+                NewlineStyle::Preserve if lines.peek().is_none() => layout.final_ending,
+                NewlineStyle::Preserve => ending,
+                NewlineStyle::Unix => "\n",
+                NewlineStyle::Windows => "\r\n",
+            };
+        }
+        output
+    }
+
+    fn junk(&self) -> &str {
+        self.junk_chars
+            .as_deref()
+            .unwrap_or_else(|| self.language.junk_chars())
+    }
+
+    // Move start-of-line to previous line's end-of-line
+    fn process_line(&self, lines: &mut [Line], idx: usize) -> Option<Warning> {
+        let line = &lines[idx];
+        if line.verbatim {
+            return None;
+        }
+        let range = line.leading_junk(self.junk())?;
+        let target = match hoist_target(lines, idx) {
+            Some(target) => target,
+            None => {
+                // Nowhere to put it, so it stays where it is
+                let junk_end = range.start + line.text[range.clone()].trim_end().len();
+                return Some(Warning::UnmovableJunk {
+                    span: line.span(range.start..junk_end),
+                });
+            }
+        };
         let line = &mut lines[idx];
-        // Move start-of-line to previous line's end-of-line
-        if let Some(captures) = START_JUNK_WS_REGEX.captures(&line.clone()) {
-            let junk_and_ws = captures.get(1).unwrap();
-            let junk_str = WS_REGEX.replace_all(junk_and_ws.as_str(), "");
-            line.replace_range(junk_and_ws.range(), "");
-            prev_line_modification = Some(junk_str.to_string());
+        let junk_str = line.text[range.clone()].to_string();
+        line.hoisted = range.clone();
+        line.replace_range(range, "");
+        lines[target].push_hoisted(&junk_str, idx - target - 1, self.junk());
+        None
+    }
+
+    fn alignment_groups(&self, lines: &[Line]) -> Vec<Range<usize>> {
+        if self.grouping == Grouping::File {
+            return std::iter::once(0..lines.len()).collect();
+        }
+        // How deep in braces each line starts and ends, and how many blocks open at each depth
+        let mut depths = Vec::with_capacity(lines.len());
+        let mut blocks = Vec::new();
+        let mut depth = 0usize;
+        for line in lines {
+            let depth_before = depth;
+            for (byte_idx, c) in line.text.char_indices() {
+                match c {
+                    '{' if line.is_code(byte_idx) => {
+                        if blocks.len() <= depth {
+                            blocks.resize(depth + 1, 0);
+                        }
+                        blocks[depth] += 1;
+                        depth += 1;
+                    }
+                    '}' if line.is_code(byte_idx) => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+            }
+            depths.push((depth_before, depth));
         }
-        // Move end-of-line outwards
-        if let Some(junk_and_ws) = END_JUNK_WS_REGEX.find(&line.clone()) {
-            let junk_str = WS_REGEX.replace_all(junk_and_ws.as_str(), "");
-            if junk_str.is_empty() {
-                // We don't need to touch this, it's just trailing whitespace
-                return;
+        // Blocks are taken from the first depth with more than one of them, so the methods of a
+        // class get grouped like the functions of a file
+        let level = blocks
+            .iter()
+            .take_while(|&&count| count > 0)
+            .position(|&count| count > 1)
+            .unwrap_or(0);
+        let mut groups = Vec::new();
+        let mut start = 0;
+        for (idx, line) in lines.iter().enumerate() {
+            let (depth_before, depth) = depths[idx];
+            let blank = line.text.trim().is_empty();
+            let split_at_blank = blank && (self.grouping == Grouping::Paragraph || depth <= level);
+            let in_block = |depth: usize| self.grouping == Grouping::Block && depth > level;
+            if !in_block(depth_before) && in_block(depth) && start < idx {
+                groups.push(start..idx);
+                start = idx;
             }
-            let size_before = junk_and_ws.start();
-            let space_count = self.junk_column.saturating_sub(size_before);
-            line.replace_range(
-                size_before..,
-                &*(format!("{}{}", " ".repeat(space_count), junk_str,)),
-            );
+            let block_ended = in_block(depth_before) && !in_block(depth);
+            if split_at_blank || block_ended {
+                let end = if blank { idx } else { idx + 1 };
+                if start < end {
+                    groups.push(start..end);
+                }
+                start = idx + 1;
+            }
+        }
+        if start < lines.len() {
+            groups.push(start..lines.len());
         }
+        groups
+    }
 
-        if let Some(modification) = prev_line_modification {
-            lines[idx - 1] += &*modification;
+    fn resolve_junk_column(&self, lines: &[Line]) -> usize {
+        match self.junk_column {
+            JunkColumn::Fixed(column) => column,
+            JunkColumn::Auto { padding, max } => {
+                let widest = lines
+                    .iter()
+                    .filter(|line| !line.verbatim && !line.is_blank_or_comment())
+                    .map(|line| line.code_width(self.junk()))
+                    .max()
+                    .unwrap_or(0);
+                let column = widest + padding;
+                max.map_or(column, |max| column.min(max))
+            }
         }
     }
 
-    // Merges junk and whitespace only lines to previous lines
-    fn collapse_lines(&self, lines: &mut Vec<String>) {
+    // Move end-of-line outwards
+    fn align_junk(&self, line: &mut Line, junk_column: usize) -> Option<Warning> {
+        if line.verbatim {
+            return None;
+        }
+        let range = line.trailing_junk(self.junk())?;
+        // Spaces within the junk are kept, so reversing can tell where it came from
+        let junk_str = line.text[range.clone()].trim();
+        // If it's empty, we don't need to touch this, it's just trailing whitespace
+        if junk_str.is_empty() {
+            return None;
+        }
+        // Junk with no code before it couldn't be moved, and was promised to stay where it is
+        if line.text[..range.start].trim().is_empty() {
+            return None;
+        }
+        let size_before = line.text[..range.start].chars().count();
+        let warning = (size_before >= junk_column).then(|| {
+            let overflow_start = line
+                .text
+                .char_indices()
+                .nth(junk_column)
+                .map_or(range.start, |(idx, _)| idx.min(range.start));
+            Warning::Overflow {
+                span: line.span(overflow_start..range.start),
+                width: size_before,
+                column: junk_column,
+            }
+        });
+        let space_count = self.junk_gap(size_before, junk_column) + usize::from(line.gap_flipped);
+        let mut aligned = format!("{}{}", " ".repeat(space_count), junk_str);
+        let mut end = range.end;
+        // Any trailing comment keeps its place after the junk
+        if let (Some(comment), Some(comment_column)) =
+            (line.trailing_comment(), self.comment_column)
+        {
+            let width = size_before + aligned.chars().count();
+            aligned += &" ".repeat(comment_column.saturating_sub(width).max(1));
+            end = comment.start;
+        }
+        line.replace_range(range.start..end, &aligned);
+        warning
+    }
+
+    // How many spaces go between code `width` characters wide and its junk
+    fn junk_gap(&self, width: usize, junk_column: usize) -> usize {
+        if width < junk_column {
+            return junk_column - width;
+        }
+        match self.overflow {
+            Overflow::Gap(gap) => gap,
+            Overflow::TabStop(stop) => {
+                let stops_past = (width - junk_column) / stop + 1;
+                junk_column + stops_past * stop - width
+            }
+        }
+    }
+
+    // Merges junk-only lines into the code before them
+    fn collapse_lines(&self, lines: &mut Vec<Line>, warnings: &mut Vec<Warning>) {
         let mut index = 1;
         while index < lines.len() {
-            let line = &mut lines[index];
-            let line_without_ws = WS_REGEX.replace_all(&line, "").to_string();
-            if !line_without_ws.is_empty()
-                && line_without_ws.chars().all(|c| JUNK_CHARS.contains(c))
-            {
-                lines[index - 1] += &*line_without_ws;
+            if lines[index].verbatim || !lines[index].is_junk_only(self.junk()) {
+                // Nothing to collapse
+            } else if let Some(target) = hoist_target(lines, index) {
+                let junk_str = lines[index].text.clone();
+                lines[target].push_hoisted(&junk_str, index - target - 1, self.junk());
                 lines.remove(index);
                 index -= 1;
+            } else if lines[index].leading_junk(self.junk()).is_none() {
+                // Indented junk gets another try, and a warning, when moving start-of-line
+                let line = &lines[index];
+                let start = line.text.len() - line.text.trim_start().len();
+                let end = line.text.trim_end().len();
+                warnings.push(Warning::UnmovableJunk {
+                    span: line.span(start..end),
+                });
             }
 
             index += 1;
@@ -110,6 +1281,534 @@ impl Formatter {
 
 impl Default for Formatter {
     fn default() -> Self {
-        Formatter { junk_column: 120 }
+        Formatter {
+            junk_column: JunkColumn::Fixed(120),
+            grouping: Grouping::File,
+            overflow: Overflow::Gap(1),
+            warn_overflow: false,
+            newline_style: NewlineStyle::Preserve,
+            junk_chars: None,
+            comment_column: None,
+            language: Language::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONVENTIONAL: &str = "\
+int f(int a) {
+    int x[] = { 1, 2 };
+    if (a) { b(); }
+    if (a) {
+        b();
+    } else {
+        c();
+    }
+    do {
+        a--;
+    } while (a);
+    while (a) {
+        a--;
+
+    }
+    if (a
+        && b) {
+        c();
+    }
+    {
+        d();
+    }
+    // done
+}
+
+int g() {
+    return 1;
+}
+";
+
+    fn formatter(junk_column: JunkColumn) -> Formatter {
+        let mut formatter = Formatter::default();
+        formatter.junk_column(junk_column);
+        formatter
+    }
+
+    fn formatted(formatter: &Formatter, content: &str) -> String {
+        let mut content = content.to_string();
+        formatter.format(&mut content).unwrap();
+        content
+    }
+
+    fn unformatted(formatter: &Formatter, content: &str) -> String {
+        let mut content = content.to_string();
+        formatter.unformat(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn conventional_code_is_already_unformatted() {
+        let formatter = formatter(JunkColumn::Fixed(40));
+        assert_eq!(unformatted(&formatter, CONVENTIONAL), CONVENTIONAL);
+    }
+
+    #[test]
+    fn unformat_undoes_format() {
+        let columns = [
+            JunkColumn::Fixed(40),
+            JunkColumn::Fixed(10),
+            JunkColumn::Auto {
+                padding: 1,
+                max: None,
+            },
+        ];
+        for junk_column in columns {
+            let formatter = formatter(junk_column);
+            let mut formatted = CONVENTIONAL.to_string();
+            formatter.format(&mut formatted).unwrap();
+            assert_ne!(formatted, CONVENTIONAL);
+            assert_eq!(unformatted(&formatter, &formatted), CONVENTIONAL);
+        }
+    }
+
+    #[test]
+    fn unformat_undoes_format_in_javascript() {
+        let content = "\
+call(function () {
+    return /}/;
+});
+";
+        let mut formatter = formatter(JunkColumn::Fixed(30));
+        formatter.language(Language::JavaScript);
+        let mut formatted = content.to_string();
+        formatter.format(&mut formatted).unwrap();
+        assert_eq!(unformatted(&formatter, &formatted), content);
+    }
+
+    #[test]
+    fn unformat_terminates_lines_split_from_the_last() {
+        let formatter = formatter(JunkColumn::Fixed(40));
+        assert_eq!(
+            unformatted(&formatter, "int f() {\n    if (a) {\n        a()  ;}}"),
+            "int f() {\n    if (a) {\n        a();\n    }\n}"
+        );
+    }
+
+    #[test]
+    fn unformat_splits_braces_closed_on_the_next_line() {
+        let content = "\
+int f() {
+    for (;;) {
+    }
+    if (a) {
+    } else {
+        b();
+    }
+}
+";
+        let formatter = formatter(JunkColumn::Fixed(20));
+        let formatted = formatted(&formatter, content);
+        assert!(formatted.contains("for (;;)        {  }\n"));
+        assert_eq!(unformatted(&formatter, &formatted), content);
+    }
+
+    #[test]
+    fn unformat_keeps_opening_braces_unspaced_in_go() {
+        let content = "\
+func f() {
+\tx := map[string]int{
+\t\t\"a\": 1,
+\t\t\"b\": 2,
+\t}
+\tg(x)
+}
+";
+        let mut formatter = formatter(JunkColumn::Fixed(30));
+        formatter.language(Language::Go);
+        let formatted = formatted(&formatter, content);
+        assert!(formatted.contains("\tx := map[string]int           {\n"));
+        assert_eq!(unformatted(&formatter, &formatted), content);
+    }
+
+    #[test]
+    fn format_hoists_junk_over_blank_and_comment_lines() {
+        let content = "\
+int f() {
+    a();
+
+    // note
+    /* block */
+}
+";
+        let formatter = formatter(JunkColumn::Fixed(20));
+        let formatted = formatted(&formatter, content);
+        assert_eq!(
+            formatted,
+            "\
+int f()             {
+    a()             ;   }
+
+    // note
+    /* block */
+"
+        );
+        assert_eq!(unformatted(&formatter, &formatted), content);
+    }
+
+    #[test]
+    fn format_stops_hoisting_at_verbatim_and_block_comment_lines() {
+        let formatter = formatter(JunkColumn::Fixed(20));
+        assert_eq!(
+            formatted(
+                &formatter,
+                "\
+int g() {
+    b(); /* open
+       close */
+}
+int h() {
+    // pythonicfmt: skip
+    c()
+
+    ;
+}
+"
+            ),
+            "\
+int g()             {
+    b(); /* open
+       close */
+}
+int h()             {
+    // pythonicfmt: skip
+    c()
+
+    ;}
+"
+        );
+    }
+
+    #[test]
+    fn junk_column_from_str() {
+        assert_eq!("40".parse::<JunkColumn>().unwrap(), JunkColumn::Fixed(40));
+        assert_eq!(
+            "auto".parse::<JunkColumn>().unwrap(),
+            JunkColumn::Auto {
+                padding: 1,
+                max: None
+            }
+        );
+        assert_eq!(
+            "AUTO:2".parse::<JunkColumn>().unwrap(),
+            JunkColumn::Auto {
+                padding: 2,
+                max: None
+            }
+        );
+        assert_eq!(
+            "auto:2:80".parse::<JunkColumn>().unwrap(),
+            JunkColumn::Auto {
+                padding: 2,
+                max: Some(80)
+            }
+        );
+        for invalid in [
+            "",
+            "x",
+            "-1",
+            "auto:0",
+            "auto:0:80",
+            "auto:",
+            "auto:x",
+            "auto:2:",
+            "auto:2:80:1",
+        ] {
+            assert!(invalid.parse::<JunkColumn>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn warnings_point_at_the_line_as_it_was() {
+        let formatter = formatter(JunkColumn::Fixed(20));
+        let mut content = "int f() {\n    // pythonicfmt: skip\n    y = 2\n    ;\n}\n".to_string();
+        let warnings = formatter.format(&mut content).unwrap();
+        let spans: Vec<_> = warnings
+            .iter()
+            .map(|warning| {
+                let span = warning.span();
+                (span.line, span.column, span.len, span.snippet.as_str())
+            })
+            .collect();
+        assert_eq!(spans, [(4, 5, 1, "    ;")]);
+    }
+
+    #[test]
+    fn format_leaves_directed_lines_alone() {
+        let formatter = formatter(JunkColumn::Fixed(20));
+        assert_eq!(
+            formatted(
+                &formatter,
+                "\
+int f() {
+    // pythonicfmt: off
+    if (a)   {  b( ) ;
+        }
+    // pythonicfmt: on
+    c();
+    // pythonicfmt: skip
+    d()    ;
+    e();
+}
+"
+            ),
+            "\
+int f()             {
+    // pythonicfmt: off
+    if (a)   {  b( ) ;
+        }
+    // pythonicfmt: on
+    c()             ;
+    // pythonicfmt: skip
+    d()    ;
+    e()             ;}
+"
+        );
+    }
+
+    #[test]
+    fn format_keeps_junk_out_of_directed_lines() {
+        let formatter = formatter(JunkColumn::Fixed(20));
+        assert_eq!(
+            formatted(
+                &formatter,
+                "\
+int f() {
+    // pythonicfmt: off
+    x = 1
+    // pythonicfmt: on
+    ;
+    // pythonicfmt: skip
+    y = 2
+    ;
+}
+"
+            ),
+            "\
+int f()             {
+    // pythonicfmt: off
+    x = 1
+    // pythonicfmt: on
+    ;
+    // pythonicfmt: skip
+    y = 2
+    ;}
+"
+        );
+    }
+
+    #[test]
+    fn format_keeps_junk_off_preprocessor_lines() {
+        let formatter = formatter(JunkColumn::Fixed(20));
+        assert_eq!(
+            formatted(
+                &formatter,
+                "\
+int f() {
+#ifdef A
+    a();
+#endif
+}
+#define M(x) \\
+    do { \\
+        f(x); \\
+    } while (0)
+int g() {
+    if (a) {
+        b();
+#ifdef X
+        c();
+#endif
+    }
+}
+int h() {
+    x();
+#define N \\
+    1
+}
+"
+            ),
+            "\
+int f()             {
+#ifdef A
+    a()             ;
+#endif
+}
+#define M(x) \\
+    do { \\
+        f(x); \\
+    } while (0)
+int g()             {
+    if (a)          {
+        b()         ;
+#ifdef X
+        c()         ;
+#endif
+    }}
+int h()             {
+    x()             ;
+#define N \\
+    1
+}
+"
+        );
+    }
+
+    #[test]
+    fn format_preserves_crlf() {
+        let formatter = formatter(JunkColumn::Fixed(12));
+        assert_eq!(
+            formatted(&formatter, "int f() {\r\n    return 1;\r\n}\r\n"),
+            "int f()     {\r\n    return 1 ;}\r\n"
+        );
+    }
+
+    #[test]
+    fn format_preserves_a_missing_final_newline() {
+        let formatter = formatter(JunkColumn::Fixed(12));
+        assert_eq!(
+            formatted(&formatter, "int f() {\n    return 1;\n}"),
+            "int f()     {\n    return 1 ;}"
+        );
+    }
+
+    #[test]
+    fn format_keeps_a_byte_order_mark() {
+        let formatter = formatter(JunkColumn::Fixed(12));
+        assert_eq!(
+            formatted(&formatter, "\u{feff}int f() {\n    return 1;\n}\n"),
+            "\u{feff}int f()     {\n    return 1 ;}\n"
+        );
+    }
+
+    #[test]
+    fn format_normalizes_newlines() {
+        let content = "\u{feff}int f() {\r\n    return 1;\n}";
+        let mut formatter = formatter(JunkColumn::Fixed(12));
+        formatter.newline_style(NewlineStyle::Unix);
+        assert_eq!(
+            formatted(&formatter, content),
+            "int f()     {\n    return 1 ;}\n"
+        );
+        formatter.newline_style(NewlineStyle::Windows);
+        assert_eq!(
+            formatted(&formatter, content),
+            "int f()     {\r\n    return 1 ;}\r\n"
+        );
+    }
+
+    #[test]
+    fn format_marks_junk_hoisted_over_blank_lines() {
+        let formatter = formatter(JunkColumn::Fixed(12));
+        assert_eq!(
+            formatted(&formatter, "int f() {\n    foo();\n\n}\n"),
+            "int f()     {\n    foo()   ; }\n\n"
+        );
+    }
+
+    #[test]
+    fn format_keeps_spaces_within_junk() {
+        let formatter = formatter(JunkColumn::Fixed(20));
+        assert_eq!(
+            formatted(&formatter, "int f() {\n    if (a) { b(); }\n}\n"),
+            "int f()             {\n    if (a) { b()    ; }}\n"
+        );
+    }
+
+    fn grouped(grouping: Grouping, language: Language, content: &str) -> String {
+        let mut formatter = formatter(JunkColumn::Auto {
+            padding: 1,
+            max: None,
+        });
+        formatter.grouping(grouping).language(language);
+        formatted(&formatter, content)
+    }
+
+    #[test]
+    fn file_grouping_aligns_every_line() {
+        let content = "int counter_of_things = 0;\nint f() {\n    g();\n}\n";
+        assert_eq!(
+            grouped(Grouping::File, Language::C, content),
+            "int counter_of_things = 0 ;\nint f()                   {\n    g()                   ;}\n"
+        );
+    }
+
+    #[test]
+    fn block_grouping_starts_a_group_at_each_block() {
+        let content = "int counter_of_things = 0;\nint f() {\n    g();\n}\n";
+        assert_eq!(
+            grouped(Grouping::Block, Language::C, content),
+            "int counter_of_things = 0 ;\nint f() {\n    g() ;}\n"
+        );
+    }
+
+    #[test]
+    fn block_grouping_uses_the_first_level_with_several_blocks() {
+        let content = "\
+class A {
+    void f() {
+        g();
+    }
+    void longerMethodName() {
+        h();
+    }
+}
+";
+        assert_eq!(
+            grouped(Grouping::Block, Language::Java, content),
+            "\
+class A {
+    void f() {
+        g()  ;}
+    void longerMethodName() {
+        h()                 ;}}
+"
+        );
+        assert_eq!(
+            grouped(Grouping::Paragraph, Language::Java, content),
+            "\
+class A                     {
+    void f()                {
+        g()                 ;}
+    void longerMethodName() {
+        h()                 ;}}
+"
+        );
+    }
+
+    #[test]
+    fn paragraph_grouping_splits_at_blank_lines() {
+        let content = "\
+class A {
+    void f() {
+        g();
+    }
+
+    void longerMethodName() {
+        h();
+    }
+}
+";
+        assert_eq!(
+            grouped(Grouping::Paragraph, Language::Java, content),
+            "\
+class A      {
+    void f() {
+        g()  ;}
+
+    void longerMethodName() {
+        h()                 ;}}
+"
+        );
     }
 }
@@ -0,0 +1,451 @@
+use crate::language::{Quote, RawStrings, Syntax};
+
+/// What a given byte of a line belongs to, lexically.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Code,
+    String,
+    Char,
+    LineComment,
+    BlockComment,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum State {
+    Code,
+    Literal(Literal),
+    // How many block comments deep we are, only ever above one if they nest
+    BlockComment(usize),
+    // Whether we're inside a `[...]` class, where `/` doesn't end the regex
+    Regex(bool),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Literal {
+    quote: Quote,
+    // Usually the quote's own, but raw strings choose theirs as they start
+    close: String,
+}
+
+// Raw strings with custom delimiters only borrow the rules from this
+const RAW_STRING: Quote = Quote {
+    open: "",
+    close: "",
+    char_literal: false,
+    escapes: false,
+    multiline: true,
+    doubled_close: false,
+    interpolation: false,
+};
+
+impl Literal {
+    fn new(quote: Quote) -> Self {
+        Literal {
+            quote,
+            close: quote.close.to_string(),
+        }
+    }
+
+    fn raw(close: String) -> Self {
+        Literal {
+            quote: RAW_STRING,
+            close,
+        }
+    }
+
+    fn kind(&self) -> Kind {
+        if self.quote.char_literal {
+            Kind::Char
+        } else {
+            Kind::String
+        }
+    }
+}
+
+/// A line-by-line lexer for C-style code.
+///
+/// It only knows enough to tell code apart from string literals, char literals and comments,
+/// which is all we need to decide what is safe to move.
+pub(crate) struct Lexer {
+    syntax: Syntax,
+    state: State,
+    // Literals we're in the `${...}` of, innermost last, each with how many braces deep into the
+    // code we are
+    interpolating: Vec<(Literal, usize)>,
+}
+
+impl Lexer {
+    pub(crate) fn new(syntax: Syntax) -> Self {
+        Lexer {
+            syntax,
+            state: State::Code,
+            interpolating: Vec::new(),
+        }
+    }
+
+    /// What the next byte will be, if it continues what came before.
+    pub(crate) fn kind(&self) -> Kind {
+        match &self.state {
+            State::Code => Kind::Code,
+            State::Literal(literal) => literal.kind(),
+            State::BlockComment(_) => Kind::BlockComment,
+            State::Regex(_) => Kind::String,
+        }
+    }
+
+    /// Classify every byte of `line`, which must not contain the line terminator.
+    pub(crate) fn lex_line(&mut self, line: &str) -> Vec<Kind> {
+        let mut kinds = Vec::with_capacity(line.len());
+        while kinds.len() < line.len() {
+            let rest = &line[kinds.len()..];
+            // Each of these leaves behind the state for what comes next
+            let (kind, len) = match std::mem::replace(&mut self.state, State::Code) {
+                State::Code if self.syntax.regex_literals && starts_regex(line, &kinds) => {
+                    self.state = State::Regex(false);
+                    (Kind::String, 1)
+                }
+                State::Code => self.lex_code(rest),
+                State::Literal(literal) => self.lex_literal(literal, rest),
+                State::BlockComment(depth) => self.lex_block_comment(depth, rest),
+                State::Regex(in_class) => self.lex_regex(in_class, rest),
+            };
+            kinds.resize(kinds.len() + len, kind);
+        }
+        match &self.state {
+            // Most literals only continue onto the next line if the newline is escaped
+            State::Literal(literal)
+                if !literal.quote.multiline
+                    && (!line.ends_with('\\') || ends_with_escaped_backslash(line)) =>
+            {
+                self.state = State::Code
+            }
+            State::Regex(_) => self.state = State::Code,
+            _ => {}
+        }
+        kinds
+    }
+
+    fn lex_code(&mut self, rest: &str) -> (Kind, usize) {
+        let c = first_char(rest);
+        if let Some(kind) = self.end_interpolation(c) {
+            return (kind, c.len_utf8());
+        }
+        if self
+            .syntax
+            .line_comments
+            .iter()
+            .any(|start| rest.starts_with(start))
+        {
+            // Everything until the end of the line is a comment
+            return (Kind::LineComment, rest.len());
+        }
+        let (open, _) = self.syntax.block_comment;
+        if rest.starts_with(open) {
+            self.state = State::BlockComment(1);
+            return (Kind::BlockComment, open.len());
+        }
+        if let Some((literal, len)) = self.raw_string(rest) {
+            self.state = State::Literal(literal);
+            return (Kind::String, len);
+        }
+        if let Some(&quote) = self.syntax.quotes.iter().find(|q| rest.starts_with(q.open)) {
+            if !quote.char_literal || !self.syntax.lifetimes || is_char_literal(rest) {
+                let literal = Literal::new(quote);
+                let kind = literal.kind();
+                self.state = State::Literal(literal);
+                return (kind, quote.open.len());
+            }
+        }
+        (Kind::Code, c.len_utf8())
+    }
+
+    // Counts braces inside `${...}`, returning to the literal at the `}` that closes it
+    fn end_interpolation(&mut self, c: char) -> Option<Kind> {
+        let depth = &mut self.interpolating.last_mut()?.1;
+        match c {
+            '{' => *depth += 1,
+            '}' if *depth > 0 => *depth -= 1,
+            '}' => {
+                let (literal, _) = self.interpolating.pop()?;
+                let kind = literal.kind();
+                self.state = State::Literal(literal);
+                return Some(kind);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    // Recognizes the start of a raw string, and how long its opening delimiter is
+    fn raw_string(&self, rest: &str) -> Option<(Literal, usize)> {
+        match self.syntax.raw_strings? {
+            RawStrings::Cpp => {
+                let after = rest.strip_prefix("R\"")?;
+                let delimiter = &after[..after.find('(')?];
+                let invalid = |c: char| c.is_whitespace() || c == ')' || c == '\\';
+                if delimiter.len() > 16 || delimiter.contains(invalid) {
+                    return None;
+                }
+                let close = format!("){}\"", delimiter);
+                Some((Literal::raw(close), "R\"(".len() + delimiter.len()))
+            }
+            RawStrings::Rust => {
+                let after = rest.strip_prefix('r')?;
+                let hashes = after.len() - after.trim_start_matches('#').len();
+                after[hashes..].strip_prefix('"')?;
+                let close = format!("\"{}", "#".repeat(hashes));
+                Some((Literal::raw(close), "r\"".len() + hashes))
+            }
+        }
+    }
+
+    fn lex_literal(&mut self, literal: Literal, rest: &str) -> (Kind, usize) {
+        let kind = literal.kind();
+        let quote = literal.quote;
+        let c = first_char(rest);
+        let mut len = c.len_utf8();
+        if quote.escapes && c == '\\' {
+            // Escapes cover the next character, whatever it is
+            len += rest[len..].chars().next().map_or(0, char::len_utf8);
+        } else if rest.starts_with(&literal.close) {
+            len = literal.close.len();
+            if !quote.doubled_close || !rest[len..].starts_with(&literal.close) {
+                return (kind, len);
+            }
+            len *= 2;
+        } else if quote.interpolation && rest.starts_with("${") {
+            self.interpolating.push((literal, 0));
+            return (kind, "${".len());
+        }
+        self.state = State::Literal(literal);
+        (kind, len)
+    }
+
+    fn lex_block_comment(&mut self, depth: usize, rest: &str) -> (Kind, usize) {
+        let (open, close) = self.syntax.block_comment;
+        if rest.starts_with(close) {
+            if depth > 1 {
+                self.state = State::BlockComment(depth - 1);
+            }
+            return (Kind::BlockComment, close.len());
+        }
+        if self.syntax.nested_block_comments && rest.starts_with(open) {
+            self.state = State::BlockComment(depth + 1);
+            return (Kind::BlockComment, open.len());
+        }
+        self.state = State::BlockComment(depth);
+        (Kind::BlockComment, first_char(rest).len_utf8())
+    }
+
+    fn lex_regex(&mut self, in_class: bool, rest: &str) -> (Kind, usize) {
+        let c = first_char(rest);
+        let mut len = c.len_utf8();
+        let in_class = match c {
+            '\\' => {
+                len += rest[len..].chars().next().map_or(0, char::len_utf8);
+                in_class
+            }
+            '[' => true,
+            ']' => false,
+            '/' if !in_class => return (Kind::String, len),
+            _ => in_class,
+        };
+        self.state = State::Regex(in_class);
+        (Kind::String, len)
+    }
+}
+
+fn first_char(s: &str) -> char {
+    s.chars().next().expect("Lexing past the end of the line")
+}
+
+// Tells `'a'` and `'\n'` apart from lifetimes and labels like `'a`
+fn is_char_literal(rest: &str) -> bool {
+    let mut chars = rest.chars().skip(1);
+    match chars.next() {
+        Some('\\') => true,
+        Some(_) => chars.next() == Some('\''),
+        None => false,
+    }
+}
+
+// Words after which a `/` starts an operand rather than dividing one
+const BEFORE_OPERAND: &[&str] = &[
+    "await",
+    "case",
+    "delete",
+    "do",
+    "else",
+    "in",
+    "instanceof",
+    "new",
+    "of",
+    "return",
+    "throw",
+    "typeof",
+    "void",
+    "yield",
+];
+
+// Tells a regex like `/}/` apart from division and comments, by what comes before the `/`
+fn starts_regex(line: &str, kinds: &[Kind]) -> bool {
+    let rest = &line[kinds.len()..];
+    if !rest.starts_with('/') || rest.starts_with("//") || rest.starts_with("/*") {
+        return false;
+    }
+    let before = &line[..kinds.len()];
+    let end = match before.char_indices().rev().find(|&(idx, c)| {
+        !c.is_whitespace() && !matches!(kinds[idx], Kind::LineComment | Kind::BlockComment)
+    }) {
+        Some((idx, _)) if kinds[idx] != Kind::Code => return false,
+        Some((idx, c)) if is_word_char(c) => idx + c.len_utf8(),
+        Some((_, c)) => return !matches!(c, ')' | ']'),
+        None => return true,
+    };
+    let word = &before[before[..end].trim_end_matches(is_word_char).len()..end];
+    BEFORE_OPERAND.contains(&word)
+}
+
+pub(crate) fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+fn ends_with_escaped_backslash(line: &str) -> bool {
+    let backslashes = line.chars().rev().take_while(|&c| c == '\\').count();
+    backslashes % 2 == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language::Language;
+
+    // Renders each byte's kind as one character, so expectations line up under the input
+    fn lex(language: Language, lines: &[&str]) -> Vec<String> {
+        let mut lexer = Lexer::new(language.syntax());
+        lines
+            .iter()
+            .map(|line| {
+                lexer
+                    .lex_line(line)
+                    .into_iter()
+                    .map(|kind| match kind {
+                        Kind::Code => '.',
+                        Kind::String => 's',
+                        Kind::Char => 'c',
+                        Kind::LineComment => '/',
+                        Kind::BlockComment => '*',
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            lex(Language::C, &[r#"a("\"}", '\'', "\\");"#]),
+            ["..sssss..cccc..ssss.."]
+        );
+    }
+
+    #[test]
+    fn escaped_newline_continues_string() {
+        assert_eq!(
+            lex(Language::C, &[r#"s = "a\"#, r#"b}"; c"#]),
+            ["....sss", "sss..."]
+        );
+    }
+
+    #[test]
+    fn block_comment_across_lines() {
+        assert_eq!(
+            lex(Language::C, &["a; /* {", "} */ b; // }"]),
+            ["...****", "****....////"]
+        );
+    }
+
+    #[test]
+    fn nested_block_comments() {
+        assert_eq!(
+            lex(Language::Rust, &["/* /* */ } */ x"]),
+            ["*************.."]
+        );
+        assert_eq!(lex(Language::C, &["/* /* */ } */"]), ["********....."]);
+    }
+
+    #[test]
+    fn lifetimes_are_code() {
+        assert_eq!(
+            lex(Language::Rust, &["fn f<'a>(x: &'a str) -> char { '{' }"]),
+            ["...............................ccc.."]
+        );
+        assert_eq!(lex(Language::Rust, &["'outer: loop {"]), [".............."]);
+    }
+
+    #[test]
+    fn byte_and_escaped_chars() {
+        assert_eq!(lex(Language::Rust, &["b'}', '\\''"]), [".ccc..cccc"]);
+    }
+
+    #[test]
+    fn regex_literals() {
+        assert_eq!(
+            lex(Language::JavaScript, &["const r = /}/;"]),
+            ["..........sss."]
+        );
+        assert_eq!(
+            lex(Language::JavaScript, &["return /[/}]\\//g.test(s);"]),
+            [".......ssssssss.........."]
+        );
+    }
+
+    #[test]
+    fn division_is_code() {
+        assert_eq!(
+            lex(Language::JavaScript, &["x = (a) / b / c[0] / 2;"]),
+            ["......................."]
+        );
+        assert_eq!(lex(Language::C, &["y = a /b/ c;"]), ["............"]);
+    }
+
+    #[test]
+    fn cpp_raw_strings() {
+        assert_eq!(
+            lex(Language::Cpp, &[r#"s = R"x(a)" }"#, r#"b)x"; }"#]),
+            ["....sssssssss", "ssss..."]
+        );
+    }
+
+    #[test]
+    fn rust_raw_strings() {
+        assert_eq!(
+            lex(Language::Rust, &[r##"s = r#"a"} \"#; }"##]),
+            ["....ssssssssss..."]
+        );
+    }
+
+    #[test]
+    fn verbatim_strings() {
+        assert_eq!(
+            lex(Language::CSharp, &[r#"s = @"a ""b"" {"; }"#]),
+            ["....ssssssssssss..."]
+        );
+    }
+
+    #[test]
+    fn text_blocks() {
+        assert_eq!(
+            lex(Language::Java, &[r#"s = """"#, r#"  "a" }"#, r#"  """; }"#]),
+            ["....sss", "sssssss", "sssss..."]
+        );
+    }
+
+    #[test]
+    fn nested_interpolation() {
+        assert_eq!(
+            lex(Language::JavaScript, &["s = `a ${ f({x: 1}) } {`; }"]),
+            ["....sssss...........ssss..."]
+        );
+    }
+}
@@ -0,0 +1,306 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+/// A C-style language, which determines what counts as junk and how to lex it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Language {
+    #[default]
+    C,
+    Cpp,
+    Java,
+    CSharp,
+    /// Also covers TypeScript
+    JavaScript,
+    Rust,
+    Go,
+    Kotlin,
+    Swift,
+    Php,
+    Css,
+}
+
+/// How a literal is delimited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Quote {
+    pub(crate) open: &'static str,
+    pub(crate) close: &'static str,
+    /// Whether this is a char literal rather than a string literal
+    pub(crate) char_literal: bool,
+    /// Whether backslash escapes are recognized
+    pub(crate) escapes: bool,
+    /// Whether the literal may span lines without escaping the newline
+    pub(crate) multiline: bool,
+    /// Whether a doubled closing delimiter stands for itself, as in C# verbatim strings
+    pub(crate) doubled_close: bool,
+    /// Whether `${` starts code inside the literal, running until the matching `}`
+    pub(crate) interpolation: bool,
+}
+
+/// Raw string literals whose closing delimiter depends on how they were opened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RawStrings {
+    /// `R"delim(...)delim"`
+    Cpp,
+    /// `r#"..."#`, with any number of `#`
+    Rust,
+}
+
+/// The lexical rules the lexer needs to tell code from literals and comments.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Syntax {
+    pub(crate) line_comments: &'static [&'static str],
+    pub(crate) block_comment: (&'static str, &'static str),
+    pub(crate) nested_block_comments: bool,
+    /// Checked in order, so longer delimiters must come before their prefixes
+    pub(crate) quotes: &'static [Quote],
+    pub(crate) raw_strings: Option<RawStrings>,
+    /// Whether a `'` may also start a lifetime or label, and so is only a char literal if it
+    /// holds exactly one (possibly escaped) character
+    pub(crate) lifetimes: bool,
+    /// Whether lines starting with `#` are preprocessor directives
+    pub(crate) preprocessor: bool,
+    /// Whether a `/` where a value is expected starts a regex literal
+    pub(crate) regex_literals: bool,
+}
+
+const fn quote(delimiter: &'static str, char_literal: bool) -> Quote {
+    Quote {
+        open: delimiter,
+        close: delimiter,
+        char_literal,
+        escapes: true,
+        multiline: false,
+        doubled_close: false,
+        interpolation: false,
+    }
+}
+
+const fn multiline(quote: Quote) -> Quote {
+    Quote {
+        multiline: true,
+        ..quote
+    }
+}
+
+const fn raw(quote: Quote) -> Quote {
+    Quote {
+        escapes: false,
+        ..quote
+    }
+}
+
+const fn interpolated(quote: Quote) -> Quote {
+    Quote {
+        interpolation: true,
+        ..quote
+    }
+}
+
+// A C# verbatim string, where `""` is how to write a quote
+const fn verbatim(open: &'static str) -> Quote {
+    Quote {
+        open,
+        doubled_close: true,
+        ..raw(multiline(quote("\"", false)))
+    }
+}
+
+const C_QUOTES: &[Quote] = &[quote("\"", false), quote("'", true)];
+const JAVA_QUOTES: &[Quote] = &[
+    multiline(quote("\"\"\"", false)),
+    quote("\"", false),
+    quote("'", true),
+];
+const CSHARP_QUOTES: &[Quote] = &[
+    verbatim("@\""),
+    verbatim("@$\""),
+    quote("\"", false),
+    quote("'", true),
+];
+const SCRIPT_QUOTES: &[Quote] = &[
+    quote("\"", false),
+    quote("'", false),
+    interpolated(multiline(quote("`", false))),
+];
+const RUST_QUOTES: &[Quote] = &[multiline(quote("\"", false)), quote("'", true)];
+const GO_QUOTES: &[Quote] = &[
+    quote("\"", false),
+    quote("'", true),
+    raw(multiline(quote("`", false))),
+];
+const KOTLIN_QUOTES: &[Quote] = &[
+    interpolated(raw(multiline(quote("\"\"\"", false)))),
+    interpolated(quote("\"", false)),
+    quote("'", true),
+];
+const STRING_QUOTES: &[Quote] = &[quote("\"", false), quote("'", false)];
+const SWIFT_QUOTES: &[Quote] = &[multiline(quote("\"\"\"", false)), quote("\"", false)];
+
+const C_SYNTAX: Syntax = Syntax {
+    line_comments: &["//"],
+    block_comment: ("/*", "*/"),
+    nested_block_comments: false,
+    quotes: C_QUOTES,
+    raw_strings: None,
+    lifetimes: false,
+    preprocessor: false,
+    regex_literals: false,
+};
+
+impl Language {
+    pub const ALL: &'static [Language] = &[
+        Language::C,
+        Language::Cpp,
+        Language::Java,
+        Language::CSharp,
+        Language::JavaScript,
+        Language::Rust,
+        Language::Go,
+        Language::Kotlin,
+        Language::Swift,
+        Language::Php,
+        Language::Css,
+    ];
+
+    pub fn from_extension(extension: &str) -> Option<Language> {
+        let language = match extension.to_ascii_lowercase().as_str() {
+            "c" | "h" => Language::C,
+            "cpp" | "cc" | "cxx" | "c++" | "hpp" | "hh" | "hxx" | "h++" | "ino" => Language::Cpp,
+            "java" => Language::Java,
+            "cs" => Language::CSharp,
+            "js" | "jsx" | "mjs" | "cjs" | "ts" | "tsx" | "mts" | "cts" => Language::JavaScript,
+            "rs" => Language::Rust,
+            "go" => Language::Go,
+            "kt" | "kts" => Language::Kotlin,
+            "swift" => Language::Swift,
+            "php" => Language::Php,
+            "css" => Language::Css,
+            _ => return None,
+        };
+        Some(language)
+    }
+
+    pub fn from_path(path: &Path) -> Option<Language> {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(Language::from_extension)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Language::C => "c",
+            Language::Cpp => "cpp",
+            Language::Java => "java",
+            Language::CSharp => "csharp",
+            Language::JavaScript => "javascript",
+            Language::Rust => "rust",
+            Language::Go => "go",
+            Language::Kotlin => "kotlin",
+            Language::Swift => "swift",
+            Language::Php => "php",
+            Language::Css => "css",
+        }
+    }
+
+    /// The characters that get moved out to the junk column.
+    pub fn junk_chars(self) -> &'static str {
+        match self {
+            // Semi-colons are optional here, so there are few of them and they mean something
+            Language::Go | Language::Kotlin | Language::Swift => "{}",
+            _ => "{};",
+        }
+    }
+
+    pub(crate) fn syntax(self) -> Syntax {
+        match self {
+            Language::C => Syntax {
+                preprocessor: true,
+                ..C_SYNTAX
+            },
+            Language::Cpp => Syntax {
+                raw_strings: Some(RawStrings::Cpp),
+                preprocessor: true,
+                ..C_SYNTAX
+            },
+            Language::Java => Syntax {
+                quotes: JAVA_QUOTES,
+                ..C_SYNTAX
+            },
+            Language::CSharp => Syntax {
+                quotes: CSHARP_QUOTES,
+                preprocessor: true,
+                ..C_SYNTAX
+            },
+            Language::JavaScript => Syntax {
+                quotes: SCRIPT_QUOTES,
+                regex_literals: true,
+                ..C_SYNTAX
+            },
+            Language::Rust => Syntax {
+                nested_block_comments: true,
+                quotes: RUST_QUOTES,
+                raw_strings: Some(RawStrings::Rust),
+                lifetimes: true,
+                ..C_SYNTAX
+            },
+            Language::Go => Syntax {
+                quotes: GO_QUOTES,
+                ..C_SYNTAX
+            },
+            Language::Kotlin => Syntax {
+                nested_block_comments: true,
+                quotes: KOTLIN_QUOTES,
+                ..C_SYNTAX
+            },
+            Language::Swift => Syntax {
+                nested_block_comments: true,
+                quotes: SWIFT_QUOTES,
+                ..C_SYNTAX
+            },
+            Language::Php => Syntax {
+                line_comments: &["//", "#"],
+                quotes: STRING_QUOTES,
+                ..C_SYNTAX
+            },
+            // `//` is not a comment in CSS, and shows up in URLs
+            Language::Css => Syntax {
+                line_comments: &[],
+                quotes: STRING_QUOTES,
+                ..C_SYNTAX
+            },
+        }
+    }
+}
+
+impl fmt::Display for Language {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("Unknown language '{0}'")]
+pub struct UnknownLanguage(String);
+
+impl FromStr for Language {
+    type Err = UnknownLanguage;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let language = match s.to_ascii_lowercase().as_str() {
+            "c++" | "cxx" => Language::Cpp,
+            "c#" | "cs" => Language::CSharp,
+            "js" | "typescript" | "ts" => Language::JavaScript,
+            "rs" => Language::Rust,
+            "kt" => Language::Kotlin,
+            name => Language::ALL
+                .iter()
+                .copied()
+                .find(|language| language.name() == name)
+                .ok_or_else(|| UnknownLanguage(s.to_string()))?,
+        };
+        Ok(language)
+    }
+}
@@ -1,16 +1,26 @@
 #![deny(warnings)]
 use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use ignore::overrides::OverrideBuilder;
+use ignore::{Walk, WalkBuilder};
+use similar::TextDiff;
 use structopt::StructOpt;
 use thiserror::Error;
 
-use crate::ezconsole::style_e;
-use crate::formatter::Formatter;
+use crate::config::{Config, Configs, Settings};
+use crate::ezconsole::{print_diagnostic, style_e, style_o, write_diagnostic, Severity};
+use crate::formatter::{Formatter, Grouping, JunkColumn, NewlineStyle, Overflow, Warning};
+use crate::language::Language;
 
+mod config;
 mod ezconsole;
 mod formatter;
+mod language;
+mod pool;
 
 /// A cursed formatting tool.
 ///
@@ -19,6 +29,20 @@ mod formatter;
 ///
 /// Providing no files will result in reading from standard input and writing to standard output.
 ///
+/// Code between "pythonicfmt: off" and "pythonicfmt: on" comments is left alone, as is the line
+/// after a "pythonicfmt: skip" comment.
+///
+/// Settings can also come from a "pythonicfmt.toml" or ".pythonicfmt.toml" file, the nearest one
+/// in each file's directory or above it being used. Its keys are the option names with
+/// underscores, e.g. "junk_column = 100", plus "exclude" for a list of .gitignore-style globs to
+/// skip, "[extensions]" for a table of extra extensions to format as a language, and
+/// "[[overrides]]" sections whose "files" globs pick out files to apply different settings to.
+/// Options given on the command line take precedence over it.
+///
+/// A single file can pick its own settings with a comment in its first five lines like
+/// "// pythonicfmt: junk-column=96 junk-chars={};", using the option names, which takes precedence
+/// over everything else.
+///
 /// Assumptions:
 ///
 /// - Your code is already formatted well. This tool does not re-format indentation to match Python.
@@ -27,28 +51,165 @@ mod formatter;
 struct PythonicFormat {
     /// The input path(s), can be files or directories
     ///
-    /// Directories will be searched recursively.
+    /// Directories will be searched recursively, skipping hidden files, version control
+    /// directories, and anything ignored by .gitignore or .ignore files.
     #[structopt(parse(from_os_str))]
     input: Vec<PathBuf>,
+    /// Only format files in directories matching this glob, can be given multiple times
+    #[structopt(long, number_of_values = 1)]
+    include: Vec<String>,
+    /// Skip files and directories matching this glob, can be given multiple times
+    #[structopt(long, number_of_values = 1)]
+    exclude: Vec<String>,
+    /// Only format files with these extensions in directories, instead of every extension of a
+    /// known language
+    ///
+    /// Prefix an extension with "+" to add it to the known ones instead, e.g. "--ext +tpp".
+    #[structopt(long, use_delimiter = true, number_of_values = 1)]
+    ext: Vec<String>,
+    /// How many directories deep to search, 0 being only the given directory itself
+    #[structopt(long)]
+    max_depth: Option<usize>,
+    /// Search hidden files and directories too
+    #[structopt(long)]
+    hidden: bool,
+    /// Don't respect .gitignore and .ignore files
+    #[structopt(long)]
+    no_ignore: bool,
     /// The column to start storing "junk" (semi-colons, braces) at
-    #[structopt(long, default_value = "120")]
-    junk_column: usize,
+    ///
+    /// Use "auto" to put it just past the widest line of code in each file, or
+    /// "auto:<padding>[:<max>]" to leave that many columns after it, at least 1, and never go past
+    /// max. Defaults to 120.
+    #[structopt(long)]
+    junk_column: Option<JunkColumn>,
+    /// Which lines share a junk column when using "--junk-column auto"
+    ///
+    /// One of: file, paragraph (each run of non-blank lines), block (each brace block, such as a C
+    /// function or the method of a Java class, and each run of non-blank lines between them).
+    /// Defaults to file.
+    #[structopt(long)]
+    grouping: Option<Grouping>,
+    /// Where to put junk on lines whose code already reaches the junk column
+    ///
+    /// Either "gap:<spaces>" to put it that many spaces after the code, or "tab:<width>" to put it
+    /// on the next multiple of width past the junk column. Defaults to gap:1.
+    #[structopt(long)]
+    overflow: Option<Overflow>,
+    /// Warn about lines whose code reaches the junk column
+    #[structopt(long)]
+    warn_overflow: bool,
+    /// The characters to treat as "junk", instead of the language's usual ones
+    ///
+    /// For example, "}" to only move closing braces, or "{};)," to also move closing parentheses
+    /// and commas.
+    #[structopt(long)]
+    junk_chars: Option<String>,
+    /// The column to line up trailing comments at, after the junk
+    ///
+    /// Without this, trailing comments keep the space they had in front of them.
+    #[structopt(long)]
+    comment_column: Option<usize>,
+    /// The language to format as, instead of guessing from each file's extension
+    ///
+    /// One of: c, cpp, java, csharp, javascript (or typescript), rust, go, kotlin, swift, php, css.
+    /// Standard input is treated as C unless this is given.
+    #[structopt(long)]
+    language: Option<Language>,
+    /// Which line terminators to write
+    ///
+    /// One of: preserve (keep each line's terminator, the final newline and any byte order mark),
+    /// unix or windows (normalize terminators, always end with a newline and drop any byte order
+    /// mark). Defaults to preserve.
+    #[structopt(long)]
+    newline_style: Option<NewlineStyle>,
+    /// Undo a previous formatting, moving junk back next to the code
+    ///
+    /// Use the same settings the code was formatted with.
+    #[structopt(long)]
+    reverse: bool,
+    /// Don't write anything, just list the files that would change, failing if there are any
+    #[structopt(long)]
+    check: bool,
+    /// Don't write anything, print a unified diff of what would change instead
+    ///
+    /// Combine with --check to fail if there are any changes.
+    #[structopt(long)]
+    diff: bool,
+    /// Never use colors, e.g. to make a --diff suitable for "git apply"
+    #[structopt(long)]
+    no_color: bool,
+    /// How many files to format at once, defaulting to the number of CPUs
+    ///
+    /// Messages about each file are still printed in the same order as when formatting one at a
+    /// time.
+    #[structopt(short, long)]
+    jobs: Option<NonZeroUsize>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Act as a git clean/smudge filter driver
+    ///
+    /// Reads standard input and writes standard output, leaving anything that isn't valid UTF-8
+    /// untouched. To keep conventional code in the repository while working on pythonic code,
+    /// configure it like so:
+    ///
+    /// git config filter.pythonic.clean "pythonicfmt filter clean %f"
+    ///
+    /// git config filter.pythonic.smudge "pythonicfmt filter smudge %f"
+    ///
+    /// echo "*.c filter=pythonic" >> .gitattributes
+    ///
+    /// Code is only converted, in either direction, when converting it back gives exactly what we
+    /// started with, so checking out a file never leaves it looking modified and conventional code
+    /// is stored as it is.
+    Filter(Filter),
+}
+
+#[derive(StructOpt)]
+enum Filter {
+    /// Convert pythonic code back to conventional code, for storing in the repository
+    Clean(FilterFile),
+    /// Convert conventional code to pythonic code, for the working tree
+    Smudge(FilterFile),
+}
+
+#[derive(StructOpt)]
+struct FilterFile {
+    /// The path of the file being filtered, used to pick its language
+    #[structopt(parse(from_os_str))]
+    path: Option<PathBuf>,
 }
 
-impl From<&PythonicFormat> for Formatter {
+impl From<&PythonicFormat> for Settings {
     fn from(args: &PythonicFormat) -> Self {
-        let mut formatter = Formatter::default();
-        formatter.junk_column(args.junk_column);
-        formatter
+        Settings {
+            junk_column: args.junk_column,
+            grouping: args.grouping,
+            overflow: args.overflow,
+            // The flag can only turn it on, not override a config file turning it on
+            warn_overflow: Some(true).filter(|_| args.warn_overflow),
+            junk_chars: args.junk_chars.clone(),
+            comment_column: args.comment_column,
+            language: args.language,
+            newline_style: args.newline_style,
+        }
     }
 }
 
 #[derive(Error, Debug)]
 enum Error {
     #[error("I/O Error occurred: {0:?}")]
-    IoError(#[from] std::io::Error),
-    #[error("Formatter error: {0:?}")]
-    FormatterError(#[from] formatter::Error),
+    Io(#[from] std::io::Error),
+    #[error("Formatter error in {0}: {1}")]
+    Formatter(String, formatter::Error),
+    #[error("{0}")]
+    Config(#[from] config::Error),
+    #[error("{0} file(s) are not formatted")]
+    Unformatted(usize),
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -56,59 +217,469 @@ type Result<T> = std::result::Result<T, Error>;
 fn main() {
     let args: PythonicFormat = PythonicFormat::from_args();
     if let Err(error) = main_for_result(args) {
-        eprintln!("{}", style_e(format!("Error: {:?}", error)).red());
+        match &error {
+            Error::Formatter(source, error) => {
+                print_diagnostic(Severity::Error, &error.to_string(), source, error.span());
+            }
+            _ => eprintln!("{}", style_e(format!("Error: {}", error)).red()),
+        }
         exit(1);
     }
 }
 
 fn main_for_result(args: PythonicFormat) -> Result<()> {
-    let formatter = Formatter::from(&args);
-    let mut any_files = false;
-    for file_res in flatten_files(args.input) {
-        any_files = true;
-        let file = file_res?;
-        eprintln!("Formatting {}", file.display());
-        let temporary = tempfile::NamedTempFile::new_in(file.parent().expect("No parent dir?"))?;
-        let pipe_in = std::fs::File::open(&file)?;
-        process_pipe(&formatter, pipe_in, temporary.as_file())?;
-        temporary.persist(&file).map_err(|e| e.error)?;
-    }
-    if !any_files {
-        eprintln!("Formatting standard input to standard output");
-        process_pipe(&formatter, std::io::stdin(), std::io::stdout())?;
+    if args.no_color {
+        console::set_colors_enabled(false);
+        console::set_colors_enabled_stderr(false);
+    }
+    let settings = Settings::from(&args);
+    let mut configs = Configs::default();
+    if let Some(Command::Filter(filter)) = &args.command {
+        return process_filter(&settings, &mut configs, filter);
+    }
+    let mut files = Vec::new();
+    for file_res in flatten_files(&args) {
+        let (file, searched) = file_res?;
+        let config = configs.for_file(&file)?;
+        let config = config.as_deref();
+        if config.is_some_and(|config| config.is_excluded(&file))
+            || (searched && !has_selected_extension(&args, config, &file))
+        {
+            continue;
+        }
+        let formatter = file_formatter(&settings, config, Some(&file));
+        files.push((file, formatter));
+    }
+    let mut unformatted = process_files(&args, &files)?;
+    if args.input.is_empty() {
+        let config = configs.for_dir(Path::new("."))?;
+        let formatter = file_formatter(&settings, config.as_deref(), None);
+        let mut output = Output::default();
+        let result = process_stdin(&formatter, &args, &mut output);
+        output.print()?;
+        if result? {
+            unformatted += 1;
+        }
     }
 
+    if args.check && unformatted > 0 {
+        return Err(Error::Unformatted(unformatted));
+    }
     Ok(())
 }
 
-fn flatten_files(files: Vec<PathBuf>) -> impl Iterator<Item = std::io::Result<PathBuf>> {
-    files.into_iter().flat_map(
-        |file| -> Box<dyn Iterator<Item = std::io::Result<PathBuf>>> {
+/// What processing a file prints, held back so files can be processed at the same time but
+/// reported one after the other.
+#[derive(Default)]
+struct Output {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+impl Output {
+    fn print(&self) -> std::io::Result<()> {
+        std::io::stderr().write_all(&self.stderr)?;
+        std::io::stdout().write_all(&self.stdout)
+    }
+}
+
+/// Formats or checks files across `--jobs` threads, returning how many needed formatting.
+///
+/// After the first error, no more files are started, but the ones already done are reported.
+fn process_files(args: &PythonicFormat, files: &[(PathBuf, Formatter)]) -> Result<usize> {
+    let threads = args
+        .jobs
+        .unwrap_or_else(|| std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN));
+    let failed = AtomicBool::new(false);
+    let mut unformatted = 0;
+    let mut first_error = None;
+    pool::run_in_order(
+        files,
+        threads,
+        |(file, formatter)| {
+            if failed.load(Ordering::Relaxed) {
+                return None;
+            }
+            let mut output = Output::default();
+            let result = process_file(formatter, args, file, &mut output);
+            if result.is_err() {
+                failed.store(true, Ordering::Relaxed);
+            }
+            Some((output, result))
+        },
+        |processed| {
+            let (output, result) = match processed {
+                Some(processed) => processed,
+                None => return,
+            };
+            match output.print().map_err(Error::from).and(result) {
+                Ok(true) => unformatted += 1,
+                Ok(false) => {}
+                Err(error) => {
+                    first_error.get_or_insert(error);
+                }
+            }
+        },
+    );
+    match first_error {
+        Some(error) => Err(error),
+        None => Ok(unformatted),
+    }
+}
+
+/// Formats a file in place, or checks it, returning whether it needed formatting.
+fn process_file(
+    formatter: &Formatter,
+    args: &PythonicFormat,
+    file: &Path,
+    output: &mut Output,
+) -> Result<bool> {
+    let source = file.display().to_string();
+    if args.check || args.diff {
+        writeln!(output.stderr, "Checking {}", source)?;
+        let original = read_input(&source, std::fs::File::open(file)?)?;
+        return check_content(formatter, args, &source, &original, output);
+    }
+    writeln!(output.stderr, "Formatting {}", source)?;
+    let temporary = tempfile::NamedTempFile::new_in(file.parent().expect("No parent dir?"))?;
+    let pipe_in = std::fs::File::open(file)?;
+    process_pipe(
+        formatter,
+        args.reverse,
+        &source,
+        pipe_in,
+        temporary.as_file(),
+        output,
+    )?;
+    temporary.persist(file).map_err(|e| e.error)?;
+    Ok(false)
+}
+
+/// Formats standard input to standard output, or checks it, returning whether it needed
+/// formatting.
+fn process_stdin(
+    formatter: &Formatter,
+    args: &PythonicFormat,
+    output: &mut Output,
+) -> Result<bool> {
+    let source = "<stdin>";
+    // Nothing else is running, so there's no need to hold this back while waiting for input
+    if args.check || args.diff {
+        eprintln!("Checking standard input");
+        let original = read_input(source, std::io::stdin())?;
+        return check_content(formatter, args, source, &original, output);
+    }
+    eprintln!("Formatting standard input to standard output");
+    process_pipe(
+        formatter,
+        args.reverse,
+        source,
+        std::io::stdin(),
+        std::io::stdout(),
+        output,
+    )?;
+    Ok(false)
+}
+
+/// Builds the formatter for a file, guessing its language from its extension, then applying its
+/// config file and the command line over that.
+fn file_formatter(settings: &Settings, config: Option<&Config>, path: Option<&Path>) -> Formatter {
+    let mut formatter = Formatter::default();
+    let language = path.and_then(|path| {
+        config
+            .and_then(|config| config.language_for(path))
+            .or_else(|| Language::from_path(path))
+    });
+    if let Some(language) = language {
+        formatter.language(language);
+    }
+    if let Some(config) = config {
+        config.apply(&mut formatter, path);
+    }
+    settings.apply(&mut formatter);
+    formatter
+}
+
+/// Expands directories, pairing each file with whether it was found by searching one.
+fn flatten_files<'a>(
+    args: &'a PythonicFormat,
+) -> impl Iterator<Item = std::io::Result<(PathBuf, bool)>> + 'a {
+    args.input.iter().flat_map(
+        move |file| -> Box<dyn Iterator<Item = std::io::Result<(PathBuf, bool)>> + 'a> {
             if file.is_dir() {
-                Box::new(
-                    walkdir::WalkDir::new(file)
-                        .into_iter()
-                        .filter_entry(|e| e.file_type().is_file())
-                        .map(|r| match r {
-                            Ok(d) => Ok(d.into_path()),
-                            Err(e) => Err(std::io::Error::from(e)),
-                        }),
-                )
+                match walk_dir(args, file) {
+                    Ok(walk) => Box::new(walk.filter_map(|r| match r {
+                        Ok(entry) if entry.file_type().is_some_and(|t| t.is_file()) => {
+                            Some(Ok((entry.into_path(), true)))
+                        }
+                        Ok(_) => None,
+                        Err(e) => Some(Err(std::io::Error::other(e))),
+                    })),
+                    Err(e) => Box::new(std::iter::once(Err(std::io::Error::other(e)))),
+                }
             } else {
-                Box::new(vec![Ok(file)].into_iter())
+                Box::new(std::iter::once(Ok((file.clone(), false))))
             }
         },
     )
 }
 
+fn walk_dir(args: &PythonicFormat, dir: &Path) -> std::result::Result<Walk, ignore::Error> {
+    let mut overrides = OverrideBuilder::new(dir);
+    for glob in &args.include {
+        overrides.add(glob)?;
+    }
+    for glob in &args.exclude {
+        overrides.add(&format!("!{}", glob))?;
+    }
+    Ok(WalkBuilder::new(dir)
+        .standard_filters(!args.no_ignore)
+        .hidden(!args.hidden)
+        .max_depth(args.max_depth)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .overrides(overrides.build()?)
+        .filter_entry(|entry| !VCS_DIRS.iter().any(|vcs| entry.file_name() == *vcs))
+        .build())
+}
+
+/// Whether a file found in a directory should be formatted, going by its extension.
+fn has_selected_extension(args: &PythonicFormat, config: Option<&Config>, path: &Path) -> bool {
+    let extension = match path.extension().and_then(|ext| ext.to_str()) {
+        Some(extension) => extension,
+        None => return false,
+    };
+    let matches = |ext: &str| ext.trim_start_matches('.').eq_ignore_ascii_case(extension);
+    let (added, only): (Vec<_>, Vec<_>) = args.ext.iter().partition(|ext| ext.starts_with('+'));
+    if added.iter().any(|ext| matches(&ext[1..])) {
+        return true;
+    }
+    if only.is_empty() {
+        Language::from_extension(extension).is_some()
+            || config.is_some_and(|config| config.language_for(path).is_some())
+    } else {
+        only.iter().any(|ext| matches(ext))
+    }
+}
+
+const VCS_DIRS: &[&str] = &[".git", ".hg", ".svn", ".bzr", "_darcs", "CVS"];
+
 fn process_pipe(
     formatter: &Formatter,
-    mut pipe_in: impl Read,
+    reverse: bool,
+    source: &str,
+    pipe_in: impl Read,
     mut pipe_out: impl Write,
+    output: &mut Output,
 ) -> Result<()> {
-    let mut content = String::new();
-    pipe_in.read_to_string(&mut content)?;
-    formatter.format(&mut content)?;
-    pipe_out.write(content.as_bytes())?;
+    let content = read_input(source, pipe_in)?;
+    let content = process_content(formatter, reverse, source, &content, &mut output.stderr)?;
+    pipe_out.write_all(content.as_bytes())?;
+    Ok(())
+}
+
+/// Reports what formatting would change without writing it, returning whether it would change
+/// anything.
+fn check_content(
+    formatter: &Formatter,
+    args: &PythonicFormat,
+    source: &str,
+    original: &str,
+    output: &mut Output,
+) -> Result<bool> {
+    let formatted = process_content(
+        formatter,
+        args.reverse,
+        source,
+        original,
+        &mut output.stderr,
+    )?;
+    if formatted == original {
+        return Ok(false);
+    }
+    if args.diff {
+        write_diff(&mut output.stdout, source, original, &formatted)?;
+    } else {
+        writeln!(output.stdout, "{}", source)?;
+    }
+    Ok(true)
+}
+
+fn write_diff(
+    mut out: impl Write,
+    source: &str,
+    original: &str,
+    formatted: &str,
+) -> std::io::Result<()> {
+    let path = source.trim_start_matches("./");
+    let diff = TextDiff::from_lines(original, formatted);
+    let unified = diff
+        .unified_diff()
+        .header(&format!("a/{}", path), &format!("b/{}", path))
+        .to_string();
+    for line in unified.split_inclusive('\n') {
+        let styled = if line.starts_with("---") || line.starts_with("+++") {
+            style_o(line).bold()
+        } else if line.starts_with("@@") {
+            style_o(line).cyan()
+        } else if line.starts_with('+') {
+            style_o(line).green()
+        } else if line.starts_with('-') {
+            style_o(line).red()
+        } else {
+            style_o(line)
+        };
+        write!(out, "{}", styled)?;
+    }
+    Ok(())
+}
+
+fn read_input(source: &str, mut pipe_in: impl Read) -> Result<String> {
+    let mut bytes = Vec::new();
+    pipe_in.read_to_end(&mut bytes)?;
+    formatter::decode(bytes).map_err(formatter_error(source))
+}
+
+fn process_content(
+    formatter: &Formatter,
+    reverse: bool,
+    source: &str,
+    content: &str,
+    errors: impl Write,
+) -> Result<String> {
+    let mut content = content.to_string();
+    if reverse {
+        formatter
+            .unformat(&mut content)
+            .map_err(formatter_error(source))?;
+    } else {
+        let warnings = formatter
+            .format(&mut content)
+            .map_err(formatter_error(source))?;
+        report_warnings(errors, source, &warnings)?;
+    }
+    Ok(content)
+}
+
+fn formatter_error(source: &str) -> impl FnOnce(formatter::Error) -> Error + '_ {
+    move |error| Error::Formatter(source.to_string(), error)
+}
+
+fn process_filter(settings: &Settings, configs: &mut Configs, filter: &Filter) -> Result<()> {
+    let (Filter::Clean(file) | Filter::Smudge(file)) = filter;
+    let config = match &file.path {
+        Some(path) => configs.for_file(path)?,
+        None => configs.for_dir(Path::new("."))?,
+    };
+    let formatter = file_formatter(settings, config.as_deref(), file.path.as_deref());
+    let source = file
+        .path
+        .as_deref()
+        .map_or_else(|| "<stdin>".to_string(), |path| path.display().to_string());
+    let mut bytes = Vec::new();
+    std::io::stdin().read_to_end(&mut bytes)?;
+    // Git expects us to hand back exactly what we can't handle
+    let output = match String::from_utf8(bytes) {
+        Ok(mut content) => {
+            match filter {
+                Filter::Clean(_) => {
+                    if !clean(&formatter, &mut content).map_err(formatter_error(&source))? {
+                        eprintln!(
+                            "{}",
+                            style_e(format!(
+                                "Leaving {} as it is, since it couldn't be formatted back exactly",
+                                source
+                            ))
+                            .yellow()
+                        );
+                    }
+                }
+                Filter::Smudge(_) => {
+                    match smudge(&formatter, &mut content).map_err(formatter_error(&source))? {
+                        Some(warnings) => report_warnings(std::io::stderr(), &source, &warnings)?,
+                        None => eprintln!(
+                            "{}",
+                            style_e(format!(
+                                "Leaving {} as it is, since its formatting couldn't be undone exactly",
+                                source
+                            ))
+                            .yellow()
+                        ),
+                    }
+                }
+            }
+            content.into_bytes()
+        }
+        Err(e) => e.into_bytes(),
+    };
+    let mut stdout = std::io::stdout();
+    stdout.write_all(&output)?;
+    stdout.flush()?;
     Ok(())
 }
+
+// Reverses the formatting of `content`, unless formatting the result wouldn't give back exactly
+// what it was, in which case it's left alone and false is returned
+fn clean(formatter: &Formatter, content: &mut String) -> formatter::Result<bool> {
+    let mut unformatted = content.clone();
+    formatter.unformat(&mut unformatted)?;
+    let mut formatted = unformatted.clone();
+    formatter.format(&mut formatted)?;
+    if formatted != *content {
+        return Ok(false);
+    }
+    *content = unformatted;
+    Ok(true)
+}
+
+// Formats `content`, unless reversing that wouldn't give back exactly what it was, in which case
+// it's left alone and there are no warnings
+fn smudge(formatter: &Formatter, content: &mut String) -> formatter::Result<Option<Vec<Warning>>> {
+    let mut formatted = content.clone();
+    let warnings = formatter.format(&mut formatted)?;
+    let mut reversed = formatted.clone();
+    formatter.unformat(&mut reversed)?;
+    if reversed != *content {
+        return Ok(None);
+    }
+    *content = formatted;
+    Ok(Some(warnings))
+}
+
+fn report_warnings(mut out: impl Write, source: &str, warnings: &[Warning]) -> Result<()> {
+    for warning in warnings {
+        write_diagnostic(
+            &mut out,
+            Severity::Warning,
+            &warning.to_string(),
+            source,
+            warning.span(),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clean_leaves_conventional_code_alone() {
+        let formatter = Formatter::default();
+        for code in ["while (x) ;\n", "if (a)  {\n    b();\n}\n"] {
+            let mut content = code.to_string();
+            assert!(!clean(&formatter, &mut content).unwrap());
+            assert_eq!(content, code);
+        }
+    }
+
+    #[test]
+    fn clean_undoes_smudge() {
+        let formatter = Formatter::default();
+        let code = "int f() {\n    return 1;\n}\n";
+        let mut content = code.to_string();
+        assert!(smudge(&formatter, &mut content).unwrap().is_some());
+        assert_ne!(content, code);
+        assert!(clean(&formatter, &mut content).unwrap());
+        assert_eq!(content, code);
+    }
+}
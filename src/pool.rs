@@ -0,0 +1,49 @@
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/// Run `work` on every job using up to `threads` threads, handing each result to `done` in the
+/// same order as the jobs, as soon as it and everything before it is finished.
+pub(crate) fn run_in_order<J, R>(
+    jobs: &[J],
+    threads: NonZeroUsize,
+    work: impl Fn(&J) -> R + Sync,
+    mut done: impl FnMut(R),
+) where
+    J: Sync,
+    R: Send,
+{
+    let next = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..threads.get().min(jobs.len()) {
+            let sender = sender.clone();
+            let (next, work) = (&next, &work);
+            scope.spawn(move || loop {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                let job = match jobs.get(idx) {
+                    Some(job) => job,
+                    None => break,
+                };
+                if sender.send((idx, work(job))).is_err() {
+                    break;
+                }
+            });
+        }
+        // Only the workers should keep the channel open
+        drop(sender);
+
+        // Results arrive in whatever order they finish, so hold on to them until it's their turn
+        let mut finished = BTreeMap::new();
+        let mut expected = 0;
+        for (idx, result) in receiver {
+            finished.insert(idx, result);
+            while let Some(result) = finished.remove(&expected) {
+                done(result);
+                expected += 1;
+            }
+        }
+    });
+}